@@ -0,0 +1,394 @@
+//! # ACPI tables
+//!
+//! Just enough of ACPI to find the interrupt controllers and timers: the RSDP provided by Limine
+//! leads to the RSDT (or XSDT on ACPI 2.0+), which lists every other system description table,
+//! including the *Multiple APIC Description Table* (MADT) and the HPET table.
+//!
+//! Tables usually live in ACPI reclaimable or NVS memory, which is in the HHDM. Those that
+//! aren't, like an RSDP in the BIOS area, are mapped into the kernel's address space instead.
+//! Those mappings are kept, and reused by later lookups.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::{
+    RSDP_REQUEST,
+    memory::{
+        addr::{PhysAddr, VirtAddr},
+        frame_allocator::{FrameSize, FrameSize4K},
+        paging, vmm,
+    },
+};
+
+/// Signature of the MADT.
+const MADT_SIGNATURE: [u8; 4] = *b"APIC";
+/// Signature of the HPET table.
+const HPET_SIGNATURE: [u8; 4] = *b"HPET";
+
+/// Generic address structure address space of system memory.
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+/// MADT entry describing an I/O APIC.
+const MADT_IO_APIC: u8 = 1;
+/// MADT entry describing an interrupt source override.
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+
+/// Table memory outside the HHDM mapped so far, as page-aligned physical ranges and the virtual
+/// address they start at.
+static MAPPINGS: Mutex<Vec<(PhysAddr, u64, VirtAddr)>> = Mutex::new(Vec::new());
+
+/// Root System Description Pointer, as defined by ACPI 1.0.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+}
+
+/// Fields added to the RSDP by ACPI 2.0.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct ExtendedRsdp {
+    rsdp: Rsdp,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by every system description table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// Fields following the header of the MADT, before its entries.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+/// Location of a register, in one of the ACPI address spaces.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct GenericAddress {
+    address_space: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtIoApic {
+    entry_type: u8,
+    length: u8,
+    id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtInterruptOverride {
+    entry_type: u8,
+    length: u8,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+/// An I/O APIC, as described by the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    /// Physical address of its registers.
+    pub address: PhysAddr,
+    /// First global system interrupt it handles.
+    pub gsi_base: u32,
+}
+
+/// Polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// An ISA IRQ that isn't identity mapped to a global system interrupt, or whose polarity or
+/// trigger mode differs from the ISA defaults.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// The interrupt controllers described by the MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+/// Errors that can occur while reading ACPI tables.
+#[derive(Debug, Clone, Copy)]
+pub enum AcpiError {
+    /// The bootloader didn't provide an RSDP.
+    NoRsdp,
+    /// The checksum of the RSDP or of the root table doesn't match its contents.
+    InvalidChecksum,
+    /// No valid table has the requested signature.
+    TableNotFound,
+    /// A table couldn't be mapped into the kernel's address space.
+    MappingFailed,
+    /// A table describes its hardware in a way that isn't supported.
+    Unsupported,
+}
+
+/// Returns a virtual address through which `size` bytes at `phys` can be read.
+fn map(phys: PhysAddr, size: u64) -> Result<VirtAddr, AcpiError> {
+    let start = phys
+        .align_down(FrameSize4K::SIZE)
+        .expect("Page size is a power of two");
+    let end = (phys + size)
+        .align_up(FrameSize4K::SIZE)
+        .map_err(|_| AcpiError::MappingFailed)?;
+
+    // The HHDM may have holes, so every page of the range has to be in it
+    let in_hhdm = {
+        let page_table = paging::page_table();
+        (0..(end - start) / FrameSize4K::SIZE).all(|page| {
+            let addr = start + page * FrameSize4K::SIZE;
+            page_table.translate(addr.as_hhdm()).is_some()
+        })
+    };
+    if in_hhdm {
+        return Ok(phys.as_hhdm());
+    }
+
+    let mut mappings = MAPPINGS.lock();
+    if let Some(&(mapped, _, virt)) = mappings
+        .iter()
+        .find(|&&(mapped, len, _)| mapped <= start && end <= mapped + len)
+    {
+        return Ok(virt + (phys - mapped));
+    }
+
+    // Mappings are never freed, tables are read again by later lookups
+    let region = vmm::kernel_space()
+        .map_mmio(start, end - start)
+        .map_err(|_| AcpiError::MappingFailed)?;
+    mappings.push((start, end - start, region.start()));
+    Ok(region.start() + (phys - start))
+}
+
+/// Returns `true` if the `len` bytes at `addr` sum to zero.
+///
+/// # Safety
+///
+/// The bytes must be mapped.
+unsafe fn checksum_valid(addr: VirtAddr, len: usize) -> bool {
+    // Safety: Guaranteed by the caller
+    let bytes = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Maps the table at `phys` and validates its checksum.
+fn table(phys: PhysAddr) -> Result<(VirtAddr, SdtHeader), AcpiError> {
+    let addr = map(phys, size_of::<SdtHeader>() as u64)?;
+    // Safety: The header was just mapped
+    let header = unsafe { addr.as_ptr::<SdtHeader>().read_unaligned() };
+    // Entries are found past the header, which the length must include
+    if (header.length as usize) < size_of::<SdtHeader>() {
+        return Err(AcpiError::Unsupported);
+    }
+    let addr = map(phys, u64::from(header.length))?;
+    // Safety: The whole table was just mapped
+    if !unsafe { checksum_valid(addr, header.length as usize) } {
+        return Err(AcpiError::InvalidChecksum);
+    }
+    Ok((addr, header))
+}
+
+/// Finds the table with `signature` through the RSDT or XSDT.
+fn find_table(signature: [u8; 4]) -> Result<(VirtAddr, SdtHeader), AcpiError> {
+    let response = RSDP_REQUEST.get_response().ok_or(AcpiError::NoRsdp)?;
+    // Depending on the base revision, Limine provides either a physical or HHDM address, even
+    // for an RSDP in reserved memory, which the HHDM doesn't map
+    let rsdp_addr = response.address() as u64;
+    let rsdp_phys = VirtAddr::try_new(rsdp_addr)
+        .ok()
+        .and_then(VirtAddr::hhdm_offset)
+        .unwrap_or(PhysAddr::new(rsdp_addr));
+
+    let addr = map(rsdp_phys, size_of::<ExtendedRsdp>() as u64)?;
+    // Safety: The RSDP was just mapped
+    let rsdp = unsafe { addr.as_ptr::<Rsdp>().read_unaligned() };
+    // Safety: Same as above
+    if !unsafe { checksum_valid(addr, size_of::<Rsdp>()) } {
+        return Err(AcpiError::InvalidChecksum);
+    }
+
+    // ACPI 2.0+ lists tables in the XSDT, with 64-bit pointers
+    let (root, entry_size) = if rsdp.revision >= 2 {
+        // Safety: Revision 2 RSDPs have the extended fields
+        let rsdp = unsafe { addr.as_ptr::<ExtendedRsdp>().read_unaligned() };
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+
+    let (root_addr, root_header) = table(root)?;
+    let entries = (root_header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for i in 0..entries {
+        let entry = root_addr + (size_of::<SdtHeader>() + i * entry_size) as u64;
+        // Safety: The entry is within the root table, which is mapped
+        let phys = unsafe {
+            if entry_size == 8 {
+                entry.as_ptr::<u64>().read_unaligned()
+            } else {
+                u64::from(entry.as_ptr::<u32>().read_unaligned())
+            }
+        };
+        // A single broken table shouldn't hide the others
+        match table(PhysAddr::new(phys)) {
+            Ok((addr, header)) if header.signature == signature => return Ok((addr, header)),
+            Ok(_) => {}
+            Err(err) => log::warn!("Skipping ACPI table at {phys:#x}: {err:?}"),
+        }
+    }
+
+    Err(AcpiError::TableNotFound)
+}
+
+impl Polarity {
+    /// Decodes the polarity bits of MPS INTI flags, where "conforming" means `default`.
+    fn from_flags(flags: u16, default: Self) -> Self {
+        match flags & 0b11 {
+            0b01 => Self::ActiveHigh,
+            0b11 => Self::ActiveLow,
+            _ => default,
+        }
+    }
+}
+
+impl TriggerMode {
+    /// Decodes the trigger mode bits of MPS INTI flags, where "conforming" means `default`.
+    fn from_flags(flags: u16, default: Self) -> Self {
+        match (flags >> 2) & 0b11 {
+            0b01 => Self::Edge,
+            0b11 => Self::Level,
+            _ => default,
+        }
+    }
+}
+
+/// Reads the I/O APICs and interrupt source overrides from the MADT.
+///
+/// # Errors
+///
+/// Returns an [`AcpiError`] if the MADT can't be found, or a table on the way is invalid.
+pub fn madt() -> Result<Madt, AcpiError> {
+    let (addr, header) = find_table(MADT_SIGNATURE)?;
+    let mut madt = Madt {
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let end = addr + u64::from(header.length);
+    let mut entry = addr + size_of::<MadtHeader>() as u64;
+    while entry + 2 <= end {
+        // Safety: Entries start with their type and length, and are within the mapped table
+        let (entry_type, length) = unsafe { (*entry.as_ptr::<u8>(), *(entry + 1).as_ptr::<u8>()) };
+        if length < 2 || entry + u64::from(length) > end {
+            break;
+        }
+
+        match entry_type {
+            MADT_IO_APIC => {
+                // Safety: The entry type guarantees the layout
+                let io_apic = unsafe { entry.as_ptr::<MadtIoApic>().read_unaligned() };
+                madt.io_apics.push(IoApicInfo {
+                    id: io_apic.id,
+                    address: PhysAddr::new(u64::from(io_apic.address)),
+                    gsi_base: io_apic.gsi_base,
+                });
+            }
+            MADT_INTERRUPT_OVERRIDE => {
+                // Safety: The entry type guarantees the layout
+                let source = unsafe { entry.as_ptr::<MadtInterruptOverride>().read_unaligned() };
+                // ISA interrupts are active high and edge triggered unless overridden
+                madt.overrides.push(InterruptOverride {
+                    irq: source.source,
+                    gsi: source.gsi,
+                    polarity: Polarity::from_flags(source.flags, Polarity::ActiveHigh),
+                    trigger: TriggerMode::from_flags(source.flags, TriggerMode::Edge),
+                });
+            }
+            _ => {}
+        }
+
+        entry += u64::from(length);
+    }
+
+    Ok(madt)
+}
+
+/// Returns the physical address of the first HPET's registers, from the HPET table.
+///
+/// # Errors
+///
+/// Returns an [`AcpiError`] if the HPET table can't be found, a table on the way is invalid, or
+/// the registers aren't in memory space.
+pub fn hpet() -> Result<PhysAddr, AcpiError> {
+    let (addr, header) = find_table(HPET_SIGNATURE)?;
+    if (header.length as usize) < size_of::<HpetTable>() {
+        return Err(AcpiError::Unsupported);
+    }
+
+    // Safety: The table is mapped, and long enough
+    let table = unsafe { addr.as_ptr::<HpetTable>().read_unaligned() };
+    if table.base_address.address_space != ADDRESS_SPACE_MEMORY {
+        return Err(AcpiError::Unsupported);
+    }
+    Ok(PhysAddr::new(table.base_address.address))
+}
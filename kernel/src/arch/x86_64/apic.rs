@@ -0,0 +1,357 @@
+//! # Local APIC
+//!
+//! Every CPU has a local APIC, which receives interrupts for it and provides a timer. It is
+//! accessed either through MMIO (xAPIC mode), or through MSRs in x2APIC mode, which is
+//! preferred when the CPU supports it.
+//!
+//! The timer counts down from an initial count at a bus-dependent rate, so it's calibrated
+//! once against the clock source at boot. It supports one-shot and periodic interrupts, and if the CPU
+//! supports it, *TSC-deadline* mode, where the interrupt fires once the TSC reaches a deadline.
+
+use alloc::boxed::Box;
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Once;
+
+use crate::{
+    arch::{
+        cpuid,
+        interrupts::{self, dispatch},
+        registers::{self, rdmsr, wrmsr},
+    },
+    memory::{
+        addr::{PhysAddr, VirtAddr},
+        vmm,
+    },
+    time::{self, Duration},
+};
+
+/// MSR holding the local APIC's physical base address and mode.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// MSR holding the TSC value at which the timer fires, in TSC-deadline mode.
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+/// MSR of the first local APIC register in x2APIC mode.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Bit of [`IA32_APIC_BASE`] enabling x2APIC mode.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// Bit of [`IA32_APIC_BASE`] enabling the local APIC.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// Bits of [`IA32_APIC_BASE`] holding the physical base address.
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Size of the xAPIC register page.
+const MMIO_SIZE: u64 = 4096;
+
+/// Bit of the spurious interrupt vector register enabling the local APIC.
+const SVR_ENABLE: u32 = 1 << 8;
+/// Bit of an LVT entry masking its interrupt.
+const LVT_MASKED: u32 = 1 << 16;
+/// Divide configuration dividing the timer's clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// Bit of the interrupt command register set while an xAPIC IPI hasn't been accepted yet.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// Bit of the interrupt command register asserting the IPI, required for fixed delivery.
+const ICR_ASSERT: u32 = 1 << 14;
+/// Destination shorthand of the interrupt command register sending an IPI to every other CPU.
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// How long the timer is calibrated against the clock source, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// Vector of the local APIC timer interrupt, right after the legacy PICs' vectors so that a
+/// spurious PIC interrupt is never mistaken for a timer tick.
+pub const TIMER_VECTOR: u8 = 0x30;
+/// Vector of spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Local APIC registers, as offsets into the xAPIC register page.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum Register {
+    Id = 0x20,
+    TaskPriority = 0x80,
+    EndOfInterrupt = 0xb0,
+    SpuriousInterrupt = 0xf0,
+    InterruptCommand = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivide = 0x3e0,
+}
+
+/// How the local APICs are accessed.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Through MSRs.
+    X2Apic,
+    /// Through the register page mapped at the given address.
+    XApic(VirtAddr),
+}
+
+/// Timer frequencies measured by [`calibrate()`].
+#[derive(Debug, Clone, Copy)]
+struct Calibration {
+    /// Timer ticks per millisecond, with a divider of 16.
+    apic_ticks_per_ms: u64,
+    /// TSC ticks per millisecond.
+    tsc_ticks_per_ms: u64,
+}
+
+/// Modes of the local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fires once after the given delay.
+    OneShot,
+    /// Fires repeatedly with the given period.
+    Periodic,
+    /// Fires once, when the TSC reaches the deadline corresponding to the given delay.
+    TscDeadline,
+}
+
+impl TimerMode {
+    /// Returns the mode bits of the LVT timer entry.
+    fn lvt_bits(self) -> u32 {
+        match self {
+            Self::OneShot => 0b00 << 17,
+            Self::Periodic => 0b01 << 17,
+            Self::TscDeadline => 0b10 << 17,
+        }
+    }
+}
+
+/// Errors that can occur while programming the local APIC timer.
+#[derive(Debug, Clone, Copy)]
+pub enum ApicError {
+    /// The CPU doesn't support TSC-deadline mode.
+    TscDeadlineUnsupported,
+    /// The delay doesn't fit in the timer's counter.
+    DelayTooLong,
+}
+
+static MODE: Once<Mode> = Once::new();
+static CALIBRATION: Once<Calibration> = Once::new();
+
+/// Number of timer interrupts handled, on all CPUs.
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn mode() -> Mode {
+    *MODE.get().expect("Local APIC is initialized")
+}
+
+fn read(register: Register) -> u32 {
+    match mode() {
+        // Safety: x2APIC registers are MSRs, and all registers used here exist.
+        // They're all 32 bits wide, so the upper half of the MSR is always zero
+        #[allow(clippy::cast_possible_truncation)]
+        Mode::X2Apic => unsafe { rdmsr(X2APIC_MSR_BASE + (register as u32 >> 4)) as u32 },
+        // Safety: The register page is mapped uncached, and registers are 16-byte aligned
+        Mode::XApic(base) => unsafe {
+            (base + u64::from(register as u32))
+                .as_ptr::<u32>()
+                .read_volatile()
+        },
+    }
+}
+
+fn write(register: Register, value: u32) {
+    match mode() {
+        // Safety: x2APIC registers are MSRs, and all registers used here exist
+        Mode::X2Apic => unsafe {
+            wrmsr(X2APIC_MSR_BASE + (register as u32 >> 4), u64::from(value));
+        },
+        // Safety: The register page is mapped uncached, and registers are 16-byte aligned
+        Mode::XApic(base) => unsafe {
+            (base + u64::from(register as u32))
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        },
+    }
+}
+
+/// Enables the calling CPU's local APIC, with the timer masked.
+fn enable() {
+    let x2apic = matches!(mode(), Mode::X2Apic);
+    // Safety: The APIC base MSR exists on every CPU with a local APIC, and only the mode bits change
+    unsafe {
+        let base = rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+        wrmsr(
+            IA32_APIC_BASE,
+            if x2apic {
+                base | APIC_BASE_X2APIC
+            } else {
+                base
+            },
+        );
+    }
+
+    write(Register::TaskPriority, 0);
+    write(Register::LvtTimer, LVT_MASKED | u32::from(TIMER_VECTOR));
+    write(
+        Register::SpuriousInterrupt,
+        SVR_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// Measures the timer and TSC frequencies against the clock source.
+fn calibrate() -> Calibration {
+    write(Register::TimerDivide, TIMER_DIVIDE_16);
+    write(
+        Register::LvtTimer,
+        LVT_MASKED | TimerMode::OneShot.lvt_bits() | u32::from(TIMER_VECTOR),
+    );
+
+    let tsc_start = registers::rdtsc();
+    write(Register::TimerInitialCount, u32::MAX);
+    time::sleep(Duration::from_millis(CALIBRATION_MS));
+    let remaining = read(Register::TimerCurrentCount);
+    let tsc_end = registers::rdtsc();
+    write(Register::TimerInitialCount, 0);
+
+    Calibration {
+        apic_ticks_per_ms: u64::from(u32::MAX - remaining) / CALIBRATION_MS,
+        tsc_ticks_per_ms: (tsc_end - tsc_start) / CALIBRATION_MS,
+    }
+}
+
+/// Initializes the bootstrap processor's local APIC, and calibrates its timer.
+///
+/// Must be called after the kernel's virtual address space has been initialized.
+///
+/// # Panics
+///
+/// Panics if the CPU has no local APIC, or if its registers can't be mapped.
+pub fn init() {
+    assert!(cpuid::has_apic(), "CPU has no local APIC");
+
+    MODE.call_once(|| {
+        if cpuid::has_x2apic() {
+            return Mode::X2Apic;
+        }
+
+        // Safety: The APIC base MSR exists on every CPU with a local APIC
+        let base = PhysAddr::new(unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_ADDR_MASK);
+        let region = vmm::kernel_space()
+            .map_mmio(base, MMIO_SIZE)
+            .expect("Should be able to map the local APIC");
+        Mode::XApic(region.start())
+    });
+
+    interrupts::register(
+        TIMER_VECTOR,
+        Box::new(|_| {
+            TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+        }),
+    )
+    .expect("The timer vector isn't an exception");
+    dispatch::set_end_of_interrupt(TIMER_VECTOR, |_| eoi());
+    // Spurious interrupts must not be acknowledged
+    interrupts::register(SPURIOUS_VECTOR, Box::new(|_| {}))
+        .expect("The spurious vector isn't an exception");
+
+    enable();
+    let calibration = *CALIBRATION.call_once(calibrate);
+    log::info!(
+        "Local APIC {} in {:?} mode, timer at {} kHz, TSC at {} MHz",
+        id(),
+        mode(),
+        calibration.apic_ticks_per_ms,
+        calibration.tsc_ticks_per_ms / 1000
+    );
+}
+
+/// Initializes the calling application processor's local APIC.
+///
+/// Must be called after [`init()`] has run on the bootstrap processor.
+pub fn init_ap() {
+    enable();
+}
+
+/// Returns the ID of the calling CPU's local APIC.
+pub fn id() -> u32 {
+    match mode() {
+        Mode::X2Apic => read(Register::Id),
+        Mode::XApic(_) => read(Register::Id) >> 24,
+    }
+}
+
+/// Signals the end of the interrupt being handled to the local APIC.
+pub fn eoi() {
+    write(Register::EndOfInterrupt, 0);
+}
+
+/// Sends an inter-processor interrupt with `vector` to every CPU except the calling one.
+pub fn send_ipi_all_excluding_self(vector: u8) {
+    let x2apic = matches!(mode(), Mode::X2Apic);
+    // In x2APIC mode the command register is a single 64-bit MSR, written at once
+    if !x2apic {
+        write(Register::InterruptCommandHigh, 0);
+    }
+    write(
+        Register::InterruptCommand,
+        ICR_ASSERT | ICR_ALL_EXCLUDING_SELF | u32::from(vector),
+    );
+
+    if !x2apic {
+        while read(Register::InterruptCommand) & ICR_DELIVERY_PENDING != 0 {
+            spin_loop();
+        }
+    }
+}
+
+/// Starts the calling CPU's timer in `mode`, firing after `us` microseconds.
+///
+/// Periodic timers keep firing every `us` microseconds until stopped.
+///
+/// # Errors
+///
+/// - [`ApicError::TscDeadlineUnsupported`] if TSC-deadline mode was requested, but isn't supported.
+/// - [`ApicError::DelayTooLong`] if the delay doesn't fit in the timer's counter.
+pub fn start_timer(mode: TimerMode, us: u64) -> Result<(), ApicError> {
+    let calibration = *CALIBRATION.get().expect("Local APIC is initialized");
+
+    if mode == TimerMode::TscDeadline {
+        if !cpuid::has_tsc_deadline() {
+            return Err(ApicError::TscDeadlineUnsupported);
+        }
+
+        write(
+            Register::LvtTimer,
+            mode.lvt_bits() | u32::from(TIMER_VECTOR),
+        );
+        let deadline = registers::rdtsc() + us * calibration.tsc_ticks_per_ms / 1000;
+        // Safety: The MSR exists, as TSC-deadline mode is supported
+        unsafe { wrmsr(IA32_TSC_DEADLINE, deadline) };
+        return Ok(());
+    }
+
+    let count = u32::try_from(us * calibration.apic_ticks_per_ms / 1000)
+        .map_err(|_| ApicError::DelayTooLong)?;
+    write(Register::TimerDivide, TIMER_DIVIDE_16);
+    write(
+        Register::LvtTimer,
+        mode.lvt_bits() | u32::from(TIMER_VECTOR),
+    );
+    write(Register::TimerInitialCount, count.max(1));
+    Ok(())
+}
+
+/// Stops the calling CPU's timer.
+pub fn stop_timer() {
+    write(Register::LvtTimer, LVT_MASKED | u32::from(TIMER_VECTOR));
+    write(Register::TimerInitialCount, 0);
+    if cpuid::has_tsc_deadline() {
+        // Safety: The MSR exists, as TSC-deadline mode is supported
+        unsafe { wrmsr(IA32_TSC_DEADLINE, 0) };
+    }
+}
+
+/// Returns the number of timer interrupts handled so far, on all CPUs.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,110 @@
+//! CPU feature detection through the `cpuid` instruction.
+
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
+/// Processor info and feature bits.
+const FEATURES: u32 = 1;
+
+/// `ECX` bit of [`FEATURES`] indicating x2APIC support.
+const ECX_X2APIC: u32 = 1 << 21;
+/// `ECX` bit of [`FEATURES`] indicating TSC-deadline mode support in the local APIC timer.
+const ECX_TSC_DEADLINE: u32 = 1 << 24;
+/// `EDX` bit of [`FEATURES`] indicating an on-chip local APIC.
+const EDX_APIC: u32 = 1 << 9;
+
+/// Time stamp counter and core crystal clock frequencies.
+const TSC_FREQUENCY: u32 = 0x15;
+/// Processor base frequency, in MHz.
+const PROCESSOR_FREQUENCY: u32 = 0x16;
+
+/// Extended processor info and feature bits.
+const EXTENDED_FEATURES: u32 = 0x8000_0001;
+
+/// `EDX` bit of [`EXTENDED_FEATURES`] indicating no-execute page support.
+const EDX_NX: u32 = 1 << 20;
+/// `EDX` bit of [`EXTENDED_FEATURES`] indicating 1 GiB page support.
+const EDX_PAGE_1GB: u32 = 1 << 26;
+
+/// Advanced power management information.
+const POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+/// `EDX` bit of [`POWER_MANAGEMENT`] indicating a TSC that runs at a constant rate in every
+/// power state.
+const EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    __cpuid(leaf)
+}
+
+/// Returns the highest supported basic leaf.
+fn max_leaf() -> u32 {
+    cpuid(0).eax
+}
+
+/// Returns the highest supported extended leaf.
+fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000).eax
+}
+
+/// Returns `true` if the CPU has a local APIC.
+pub fn has_apic() -> bool {
+    cpuid(FEATURES).edx & EDX_APIC != 0
+}
+
+/// Returns `true` if the local APIC supports x2APIC mode.
+pub fn has_x2apic() -> bool {
+    cpuid(FEATURES).ecx & ECX_X2APIC != 0
+}
+
+/// Returns `true` if the local APIC timer supports TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    cpuid(FEATURES).ecx & ECX_TSC_DEADLINE != 0
+}
+
+/// Returns `true` if the time stamp counter runs at a constant rate in every power state.
+pub fn has_invariant_tsc() -> bool {
+    max_extended_leaf() >= POWER_MANAGEMENT && cpuid(POWER_MANAGEMENT).edx & EDX_INVARIANT_TSC != 0
+}
+
+/// Returns the frequency of the time stamp counter in Hz, if the CPU enumerates it.
+///
+/// The TSC runs at a ratio of the core crystal clock. When the crystal's frequency isn't
+/// enumerated, it is derived from the processor's base frequency instead.
+pub fn tsc_frequency() -> Option<u64> {
+    if max_leaf() < TSC_FREQUENCY {
+        return None;
+    }
+
+    let CpuidResult {
+        eax: denominator,
+        ebx: numerator,
+        ecx: crystal,
+        ..
+    } = cpuid(TSC_FREQUENCY);
+    if denominator == 0 || numerator == 0 {
+        return None;
+    }
+
+    if crystal != 0 {
+        return Some(u64::from(crystal) * u64::from(numerator) / u64::from(denominator));
+    }
+
+    // The base frequency is the TSC frequency, rounded to the MHz
+    if max_leaf() >= PROCESSOR_FREQUENCY {
+        let base_mhz = cpuid(PROCESSOR_FREQUENCY).eax & 0xffff;
+        if base_mhz != 0 {
+            return Some(u64::from(base_mhz) * 1_000_000);
+        }
+    }
+    None
+}
+
+/// Returns `true` if the CPU supports the no-execute page table bit.
+pub fn has_nx() -> bool {
+    max_extended_leaf() >= EXTENDED_FEATURES && cpuid(EXTENDED_FEATURES).edx & EDX_NX != 0
+}
+
+/// Returns `true` if the CPU supports 1 GiB pages.
+pub fn has_1g_pages() -> bool {
+    max_extended_leaf() >= EXTENDED_FEATURES && cpuid(EXTENDED_FEATURES).edx & EDX_PAGE_1GB != 0
+}
@@ -1,17 +1,31 @@
-use core::{arch::asm, ptr::addr_of};
+use core::arch::asm;
 
-use crate::arch::x86_64::PrivilegeLevel;
+use alloc::boxed::Box;
 
-const GDT_ENTRIES: usize = 3;
+use crate::arch::x86_64::{
+    PrivilegeLevel,
+    tss::{self, TaskStateSegment},
+};
 
+const GDT_ENTRIES: usize = 7;
+
+// `SYSCALL` and `SYSRET` derive their selectors from a base selector, so the order of these
+// segments is fixed: kernel code and data, then user data and code
 pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Kernel);
 const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Kernel);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::User);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::User);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Kernel);
 
 // We need to specify to the linker that this should be in the `.data` segment
 // as otherwise the GDT will get put in `.rodata` which gets mapped to a readonly page
-// and panics when the CPU attempts to write the accessed flag
+// and panics when the CPU attempts to write the accessed flag.
+// It is also patched at runtime to point the TSS descriptor at the TSS.
 #[unsafe(link_section = ".data.gdt")]
-static GDT: [GdtEntry; GDT_ENTRIES] = [
+static mut BSP_GDT: [GdtEntry; GDT_ENTRIES] = GDT_TEMPLATE;
+
+/// The GDT every CPU starts from. Only the TSS descriptor differs between CPUs.
+const GDT_TEMPLATE: [GdtEntry; GDT_ENTRIES] = [
     // Null descriptor
     GdtEntry::new(0, GdtEntryFlags::empty()),
     // Kernel code segment
@@ -31,6 +45,26 @@ static GDT: [GdtEntry; GDT_ENTRIES] = [
             | GdtAccessFlags::RW,
         GdtEntryFlags::LONG_MODE,
     ),
+    // User data segment
+    GdtEntry::new(
+        GdtAccessFlags::PRESENT
+            | GdtAccessFlags::USER
+            | GdtAccessFlags::DESCRIPTOR_TYPE
+            | GdtAccessFlags::RW,
+        GdtEntryFlags::LONG_MODE,
+    ),
+    // User code segment
+    GdtEntry::new(
+        GdtAccessFlags::PRESENT
+            | GdtAccessFlags::USER
+            | GdtAccessFlags::DESCRIPTOR_TYPE
+            | GdtAccessFlags::EXECUTABLE
+            | GdtAccessFlags::RW,
+        GdtEntryFlags::LONG_MODE,
+    ),
+    // Task state segment, filled in by `load()`
+    GdtEntry::new(0, GdtEntryFlags::empty()),
+    GdtEntry::new(0, GdtEntryFlags::empty()),
 ];
 
 bitflags::bitflags! {
@@ -49,7 +83,9 @@ impl GdtAccessFlags {
     const DESCRIPTOR_TYPE: u8 = 1 << 4;
     const EXECUTABLE: u8 = 1 << 3;
     const KERNEL: u8 = 0 << 5;
+    const USER: u8 = 3 << 5;
     const PRESENT: u8 = 1 << 7;
+    const TSS_AVAILABLE: u8 = 0x9;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -74,6 +110,30 @@ impl GdtEntry {
             base_high: 0x00,
         }
     }
+
+    /// Creates the two entries making up the system segment descriptor of a 64-bit TSS.
+    #[allow(clippy::cast_possible_truncation)] // We explicitely want truncation to occur in these casts
+    const fn tss(base: u64, limit: u16) -> [Self; 2] {
+        [
+            Self {
+                limit_low: limit,
+                base_low: base as u16,
+                base_middle: (base >> 16) as u8,
+                access: GdtAccessFlags::PRESENT | GdtAccessFlags::TSS_AVAILABLE,
+                limit_high_flags: 0x00,
+                base_high: (base >> 24) as u8,
+            },
+            // The upper half only holds bits 32..64 of the base
+            Self {
+                limit_low: (base >> 32) as u16,
+                base_low: (base >> 48) as u16,
+                base_middle: 0x00,
+                access: 0x00,
+                limit_high_flags: 0x00,
+                base_high: 0x00,
+            },
+        ]
+    }
 }
 
 #[repr(C, packed)]
@@ -97,15 +157,52 @@ impl SegmentSelector {
     const fn new(index: u16, privilege: PrivilegeLevel) -> Self {
         Self(index << 3 | (privilege as u16))
     }
+
+    /// Returns the raw selector, as loaded into a segment register.
+    pub const fn as_u16(self) -> u16 {
+        self.0
+    }
+}
+
+/// Loads the bootstrap processor's GDT and TSS.
+///
+/// Returns the TSS, for the per-CPU data.
+pub fn init() -> *mut TaskStateSegment {
+    // Safety: This runs once, on the bootstrap processor, before anything else uses the GDT or TSS
+    unsafe {
+        let tss = tss::init_bsp();
+        load(&raw mut BSP_GDT, tss);
+        tss
+    }
 }
 
-pub fn init() {
+/// Loads a new GDT for an application processor, describing `tss`.
+pub fn init_ap(tss: *mut TaskStateSegment) {
+    let gdt = Box::into_raw(Box::new(GDT_TEMPLATE));
+    // Safety: The GDT was just allocated and is never freed, and the TSS belongs to this CPU
+    unsafe { load(gdt, tss) };
+}
+
+/// Points the TSS descriptor of `gdt` at `tss`, then loads both along with the kernel segments.
+///
+/// # Safety
+///
+/// `gdt` and `tss` must stay valid forever, and must not be used by any other CPU.
+unsafe fn load(gdt: *mut [GdtEntry; GDT_ENTRIES], tss: *mut TaskStateSegment) {
+    #[allow(clippy::cast_possible_truncation)] // The TSS is 104 bytes
+    let [tss_low, tss_high] = GdtEntry::tss(tss as u64, (size_of::<TaskStateSegment>() - 1) as u16);
+    // Safety: The GDT isn't loaded yet, so nothing else accesses it
+    unsafe {
+        (*gdt)[5] = tss_low;
+        (*gdt)[6] = tss_high;
+    }
+
     // Truncation is never possible here, as the GDT has a hard limit of 65536 bytes
     // which is the maximum value storable in a u16
     #[allow(clippy::cast_possible_truncation)]
     let descriptor = GdtDescriptor::new(
         (core::mem::size_of::<[GdtEntry; GDT_ENTRIES]>() - 1) as u16,
-        addr_of!(GDT) as u64,
+        gdt as u64,
     );
 
     log::debug!("GDT Descriptor: {descriptor:x?}");
@@ -121,6 +218,8 @@ pub fn init() {
         load_fs(KERNEL_DATA_SELECTOR);
         load_gs(KERNEL_DATA_SELECTOR);
         load_ss(KERNEL_DATA_SELECTOR);
+
+        load_tss(TSS_SELECTOR);
     }
 }
 
@@ -161,3 +260,7 @@ unsafe fn load_gs(selector: SegmentSelector) {
 unsafe fn load_ss(selector: SegmentSelector) {
     unsafe { asm!("mov ss, {0:x}", in(reg) selector.0) };
 }
+
+unsafe fn load_tss(selector: SegmentSelector) {
+    unsafe { asm!("ltr {0:x}", in(reg) selector.0, options(nostack, preserves_flags)) };
+}
@@ -0,0 +1,274 @@
+//! # Interrupt dispatch
+//!
+//! Every vector not claimed by a dedicated handler, like the CPU exceptions, points to a small
+//! stub that pushes its vector number and jumps to a common entry point. That entry point saves
+//! the interrupted registers and calls [`dispatch()`], which runs the closures registered for
+//! the vector with [`register()`], in registration order.
+//!
+//! Several handlers can share a vector, which is how devices share an IRQ line. Vectors owned by
+//! an interrupt controller can also have an end of interrupt hook, which runs after the
+//! handlers, and once even if there is none.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::{Mutex, Once, RwLock};
+
+use crate::{
+    arch::{
+        apic,
+        interrupts::{
+            handler::{HandlerFunc, InterruptStackFrame},
+            idt::IDT,
+            without_interrupts,
+        },
+    },
+    pop_preserved, pop_scratch, push_preserved, swapgs_if_user,
+};
+
+/// Number of interrupt vectors.
+const VECTORS: usize = 256;
+
+/// Size in bytes of each stub. Stubs are aligned to this, so stub `n` is at `n * STUB_SIZE`.
+const STUB_SIZE: usize = 16;
+
+/// Vectors below this are CPU exceptions, which have dedicated handlers.
+const FIRST_INTERRUPT_VECTOR: u8 = 32;
+
+/// First vector handed out by [`allocate_vector()`], after the exceptions, the legacy PICs'
+/// vectors 0x20 to 0x2f and the local APIC timer's vector.
+const FIRST_DYNAMIC_VECTOR: u8 = apic::TIMER_VECTOR + 1;
+/// Vector past the last one handed out by [`allocate_vector()`], which is the local APIC's
+/// spurious interrupt vector.
+const END_DYNAMIC_VECTOR: u8 = 0xff;
+
+/// A handler registered for a vector.
+pub type Handler = Box<dyn Fn(&mut InterruptStackFrame) + Send + Sync>;
+
+/// Identifies a registered handler, to unregister it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    /// Returns the vector the handler is registered for.
+    pub fn vector(self) -> u8 {
+        self.vector
+    }
+}
+
+/// Errors that can occur while registering a handler.
+#[derive(Debug, Clone, Copy)]
+pub enum DispatchError {
+    /// The vector is a CPU exception, which isn't dispatched.
+    ExceptionVector,
+    /// Every vector available to devices already has handlers.
+    NoFreeVector,
+    /// The handler isn't registered.
+    UnknownHandler,
+}
+
+struct Vector {
+    handlers: RwLock<Vec<(u64, Handler)>>,
+    end_of_interrupt: Once<fn(u8)>,
+    hits: AtomicU64,
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Self {
+            handlers: RwLock::new(Vec::new()),
+            end_of_interrupt: Once::new(),
+            hits: AtomicU64::new(0),
+        }
+    }
+}
+
+static VECTOR_TABLE: [Vector; VECTORS] = [const { Vector::new() }; VECTORS];
+
+/// Serializes [`allocate_vector()`], so two callers can't be handed the same vector.
+static ALLOCATION: Mutex<()> = Mutex::new(());
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The interrupt stubs, [`STUB_SIZE`] bytes apart.
+///
+/// The CPU only pushes an error code for some exceptions, so the other stubs push a zero in its
+/// place, which gives [`interrupt_common`] the same stack layout for every vector.
+#[unsafe(naked)]
+unsafe extern "C" fn interrupt_stubs() {
+    core::arch::naked_asm!(
+        ".set stub_vector, 0",
+        ".rept 256",
+        ".balign 16",
+        ".if stub_vector < 32",
+        // Double fault, invalid TSS, segment not present, stack segment fault, general protection
+        // fault, page fault, alignment check, control protection, VMM communication and security
+        // exceptions push an error code
+        ".if ((0x60227d00 >> stub_vector) & 1) == 0",
+        "pushq $0",
+        ".endif",
+        ".else",
+        "pushq $0",
+        ".endif",
+        "pushq $stub_vector",
+        "jmp {common}",
+        ".set stub_vector, stub_vector + 1",
+        ".endr",
+        common = sym interrupt_common,
+        options(att_syntax),
+    );
+}
+
+/// Saves the interrupted registers, and calls [`dispatch()`] with the vector and error code the
+/// stub left on the stack.
+#[unsafe(naked)]
+unsafe extern "C" fn interrupt_common() {
+    core::arch::naked_asm!(
+        concat!(
+            swapgs_if_user!(24),
+            "cld\n",
+            // The vector and error code slots become those of `rcx` and `rax`
+            "xchg rax, [rsp + 8]\n",
+            "xchg rcx, [rsp]\n",
+            "push rdx\n",
+            "push rdi\n",
+            "push rsi\n",
+            "push r8\n",
+            "push r9\n",
+            "push r10\n",
+            "push r11\n",
+            push_preserved!(),
+            "mov rdi, rsp\n",
+            "mov rsi, rcx\n",
+            "mov rdx, rax\n",
+            "call {dispatch}\n",
+            pop_preserved!(),
+            pop_scratch!(),
+            "pop rax\n",
+            swapgs_if_user!(8),
+            "iretq\n"
+        ),
+        dispatch = sym dispatch,
+    );
+}
+
+extern "C" fn dispatch(stack: &mut InterruptStackFrame, vector: u64, error_code: u64) {
+    let vector = u8::try_from(vector).expect("Stubs push valid vectors");
+    // Reserved exceptions have no dedicated handler, and returning would just fault again
+    if vector < FIRST_INTERRUPT_VECTOR {
+        stack.dump();
+        panic!("Unexpected exception {vector} with error code: {error_code}");
+    }
+
+    let entry = &VECTOR_TABLE[usize::from(vector)];
+    entry.hits.fetch_add(1, Ordering::Relaxed);
+
+    let handlers = entry.handlers.read();
+    for (_, handler) in handlers.iter() {
+        handler(stack);
+    }
+    drop(handlers);
+
+    match entry.end_of_interrupt.get() {
+        Some(end_of_interrupt) => end_of_interrupt(vector),
+        None if entry.handlers.read().is_empty() => {
+            log::warn!("Unhandled interrupt {vector:#x} (error code {error_code:#x})");
+        }
+        None => {}
+    }
+}
+
+/// Points every vector at its stub.
+///
+/// Dedicated handlers, like those of the CPU exceptions, must be set afterwards.
+pub fn init() {
+    // The first stub is aligned too, so it starts after padding if the function itself isn't
+    let base = (interrupt_stubs as *const () as usize).next_multiple_of(STUB_SIZE);
+    let mut idt = IDT.lock();
+    for vector in 0..VECTORS {
+        // Safety: Stubs are `STUB_SIZE` bytes apart, and each one is a valid interrupt handler
+        unsafe {
+            let stub: HandlerFunc = core::mem::transmute(base + vector * STUB_SIZE);
+            idt.set_handler(vector, stub, 0);
+        }
+    }
+}
+
+/// Registers `handler` to run whenever interrupt `vector` fires, after any handler already
+/// registered for it.
+///
+/// Handlers run with interrupts disabled, and must not register or unregister handlers for
+/// their own vector.
+///
+/// # Errors
+///
+/// Returns [`DispatchError::ExceptionVector`] if `vector` is a CPU exception.
+pub fn register(vector: u8, handler: Handler) -> Result<HandlerId, DispatchError> {
+    if vector < FIRST_INTERRUPT_VECTOR {
+        return Err(DispatchError::ExceptionVector);
+    }
+
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    // Interrupts are disabled so that a handler for this vector can't deadlock with us
+    without_interrupts(|| {
+        VECTOR_TABLE[usize::from(vector)]
+            .handlers
+            .write()
+            .push((id, handler));
+    });
+    Ok(HandlerId { vector, id })
+}
+
+/// Registers `handler` for the first vector available to devices that has no handler yet.
+///
+/// # Errors
+///
+/// Returns [`DispatchError::NoFreeVector`] if every such vector already has handlers.
+pub fn allocate_vector(handler: Handler) -> Result<HandlerId, DispatchError> {
+    let _guard = ALLOCATION.lock();
+    let vector = (FIRST_DYNAMIC_VECTOR..END_DYNAMIC_VECTOR)
+        .find(|&vector| {
+            let entry = &VECTOR_TABLE[usize::from(vector)];
+            entry.end_of_interrupt.get().is_none() && entry.handlers.read().is_empty()
+        })
+        .ok_or(DispatchError::NoFreeVector)?;
+    register(vector, handler)
+}
+
+/// Unregisters a handler.
+///
+/// # Errors
+///
+/// Returns [`DispatchError::UnknownHandler`] if the handler was already unregistered.
+pub fn unregister(handler: HandlerId) -> Result<(), DispatchError> {
+    without_interrupts(|| {
+        let mut handlers = VECTOR_TABLE[usize::from(handler.vector)].handlers.write();
+        let index = handlers
+            .iter()
+            .position(|(id, _)| *id == handler.id)
+            .ok_or(DispatchError::UnknownHandler)?;
+        drop(handlers.remove(index));
+        Ok(())
+    })
+}
+
+/// Makes `end_of_interrupt` run with the vector number after the handlers of `vector`, every
+/// time it fires.
+///
+/// Only the first hook set for a vector is kept, as vectors belong to a single interrupt
+/// controller.
+pub fn set_end_of_interrupt(vector: u8, end_of_interrupt: fn(u8)) {
+    VECTOR_TABLE[usize::from(vector)]
+        .end_of_interrupt
+        .call_once(|| end_of_interrupt);
+}
+
+/// Returns the number of times interrupt `vector` was dispatched.
+pub fn hits(vector: u8) -> u64 {
+    VECTOR_TABLE[usize::from(vector)]
+        .hits
+        .load(Ordering::Relaxed)
+}
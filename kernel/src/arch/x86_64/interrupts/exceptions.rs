@@ -1,4 +1,12 @@
-use crate::{arch::interrupts::idt::IDT, interrupt_error, interrupt_stack};
+use crate::{
+    arch::{interrupts::idt::IDT, registers, x86_64::tss},
+    interrupt_error, interrupt_stack,
+    memory::{
+        addr::VirtAddr,
+        fault::{self, PageFault, PageFaultErrorCode},
+        stack,
+    },
+};
 
 interrupt_stack!(divide_by_zero, |stack| {
     stack.dump();
@@ -42,6 +50,13 @@ interrupt_stack!(device_not_available, |stack| {
 
 interrupt_error!(double_fault, |stack, error_code| {
     stack.dump();
+
+    // Overflowing a kernel stack faults on its guard page, and the CPU then fails to push the
+    // page fault's exception frame, so `CR2` still holds an address in the guard page
+    let addr = registers::read_cr2();
+    if let Some(task) = stack::overflowed_stack(addr) {
+        panic!("Kernel stack overflow in {task} (accessed {addr:?})")
+    }
     panic!("Double fault exception with error code: {}", error_code)
 });
 
@@ -75,8 +90,23 @@ interrupt_error!(general_protection_fault, |stack, error_code| {
 });
 
 interrupt_error!(page_fault, |stack, error_code| {
-    stack.dump();
-    panic!("Page fault exception with error code: {}", error_code)
+    let fault = PageFault {
+        addr: registers::read_cr2(),
+        error_code: PageFaultErrorCode::from_bits_retain(error_code),
+        ip: VirtAddr::new_truncate(stack.iret.rip),
+    };
+
+    if let Err(reason) = fault::handle(&fault) {
+        stack.dump();
+        fault.report();
+        if let Some(task) = stack::overflowed_stack(fault.addr) {
+            panic!(
+                "Kernel stack overflow in {task} (accessed {:?})",
+                fault.addr
+            )
+        }
+        panic!("Unresolved page fault at {:?}: {reason}", fault.addr)
+    }
 });
 
 interrupt_stack!(x87_floating_point, |stack| {
@@ -133,28 +163,28 @@ interrupt_error!(security_exception, |stack, error_code| {
 pub fn register_exceptions() {
     let mut idt = IDT.lock();
     unsafe {
-        idt.set_handler(0, divide_by_zero);
-        idt.set_handler(1, debug);
-        idt.set_handler(2, non_maskable_interrupt);
-        idt.set_handler(3, breakpoint);
-        idt.set_handler(4, overflow);
-        idt.set_handler(5, bound_range_exceeded);
-        idt.set_handler(6, invalid_opcode);
-        idt.set_handler(7, device_not_available);
-        idt.set_handler(8, double_fault);
-        idt.set_handler(10, invalid_tss);
-        idt.set_handler(11, segment_not_present);
-        idt.set_handler(12, stack_segment_fault);
-        idt.set_handler(13, general_protection_fault);
-        idt.set_handler(14, page_fault);
-        idt.set_handler(16, x87_floating_point);
-        idt.set_handler(17, alignment_check);
-        idt.set_handler(18, machine_check);
-        idt.set_handler(19, simd_floating_point);
-        idt.set_handler(20, virtualization);
-        idt.set_handler(21, control_protection);
-        idt.set_handler(28, hypervisor_injection);
-        idt.set_handler(29, vmm_communication);
-        idt.set_handler(30, security_exception);
+        idt.set_handler(0, divide_by_zero, 0);
+        idt.set_handler(1, debug, 0);
+        idt.set_handler(2, non_maskable_interrupt, tss::NMI_IST);
+        idt.set_handler(3, breakpoint, 0);
+        idt.set_handler(4, overflow, 0);
+        idt.set_handler(5, bound_range_exceeded, 0);
+        idt.set_handler(6, invalid_opcode, 0);
+        idt.set_handler(7, device_not_available, 0);
+        idt.set_handler(8, double_fault, tss::DOUBLE_FAULT_IST);
+        idt.set_handler(10, invalid_tss, 0);
+        idt.set_handler(11, segment_not_present, 0);
+        idt.set_handler(12, stack_segment_fault, 0);
+        idt.set_handler(13, general_protection_fault, 0);
+        idt.set_handler(14, page_fault, 0);
+        idt.set_handler(16, x87_floating_point, 0);
+        idt.set_handler(17, alignment_check, 0);
+        idt.set_handler(18, machine_check, tss::MACHINE_CHECK_IST);
+        idt.set_handler(19, simd_floating_point, 0);
+        idt.set_handler(20, virtualization, 0);
+        idt.set_handler(21, control_protection, 0);
+        idt.set_handler(28, hypervisor_injection, 0);
+        idt.set_handler(29, vmm_communication, 0);
+        idt.set_handler(30, security_exception, 0);
     }
 }
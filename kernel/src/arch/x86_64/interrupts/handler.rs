@@ -67,10 +67,13 @@ impl IretRegisters {
     }
 }
 
+/// Registers saved on the stack by the interrupt entry code.
+///
+/// The preserved registers are pushed last, so they come first.
 #[repr(C)]
 pub struct InterruptStackFrame {
-    pub scratch: ScratchRegisters,
     pub preserved: PreservedRegisters,
+    pub scratch: ScratchRegisters,
     pub iret: IretRegisters,
 }
 
@@ -142,9 +145,28 @@ macro_rules! pop_preserved {
     };
 }
 
+/// Swaps in the kernel's `GS` base if the interrupt arrived from ring 3, or swaps the user's
+/// back in if it returns there. `$cs_offset` is the offset of the saved `CS` from `rsp`.
+#[macro_export]
+macro_rules! swapgs_if_user {
+    ($cs_offset:literal) => {
+        concat!(
+            "test qword ptr [rsp + ",
+            $cs_offset,
+            "], 3\n",
+            "jz 2f\n",
+            "swapgs\n",
+            "2:\n"
+        )
+    };
+}
+
 #[macro_export]
 macro_rules! interrupt_stack {
     ($name:ident, |$stack:ident| $code:block) => {
+        /// # Safety
+        ///
+        /// Must only be called by the CPU, through the IDT.
         #[unsafe(naked)]
         pub unsafe extern "C" fn $name() {
             extern "C" fn inner($stack: &mut $crate::arch::interrupts::handler::InterruptStackFrame) {
@@ -152,6 +174,7 @@ macro_rules! interrupt_stack {
             }
 
             core::arch::naked_asm!(concat!(
+                $crate::swapgs_if_user!(8),
                 "cld;",
                 "push rax\n",
                 $crate::push_scratch!(),
@@ -160,6 +183,8 @@ macro_rules! interrupt_stack {
                 "call {inner}",
                 $crate::pop_preserved!(),
                 $crate::pop_scratch!(),
+                "pop rax\n",
+                $crate::swapgs_if_user!(8),
                 "iretq\n"
             ), inner = sym inner,);
         }
@@ -169,6 +194,9 @@ macro_rules! interrupt_stack {
 #[macro_export]
 macro_rules! interrupt_error {
     ($name:ident, |$stack:ident, $error_code:ident| $code:block) => {
+        /// # Safety
+        ///
+        /// Must only be called by the CPU, through the IDT.
         #[unsafe(naked)]
         pub unsafe extern "C" fn $name() {
             extern "C" fn inner($stack: &mut $crate::arch::interrupts::handler::InterruptStackFrame, $error_code: u64) {
@@ -176,6 +204,7 @@ macro_rules! interrupt_error {
             }
 
             core::arch::naked_asm!(concat!(
+                $crate::swapgs_if_user!(16),
                 "cld;",
                 $crate::push_scratch!(),
                 $crate::push_preserved!(),
@@ -185,6 +214,8 @@ macro_rules! interrupt_error {
                 "call {inner}",
                 $crate::pop_preserved!(),
                 $crate::pop_scratch!(),
+                "pop rax\n",
+                $crate::swapgs_if_user!(8),
                 "iretq\n"
             ), inner = sym inner,
                 rax_offset = const(::core::mem::size_of::<$crate::arch::x86_64::interrupts::handler::PreservedRegisters>() + ::core::mem::size_of::<$crate::arch::x86_64::interrupts::handler::ScratchRegisters>() - 8),
@@ -29,10 +29,14 @@ impl Idt {
         }
     }
 
-    pub unsafe fn set_handler(&mut self, index: usize, handler: HandlerFunc) {
+    /// Sets the handler of interrupt `index`, which runs on the stack in IST entry `ist`.
+    ///
+    /// An `ist` of 0 keeps running on the interrupted stack.
+    pub unsafe fn set_handler(&mut self, index: usize, handler: HandlerFunc, ist: u8) {
         unsafe {
             self.entries[index].set_handler(handler);
         }
+        self.entries[index].set_stack_index(ist);
     }
 }
 
@@ -114,6 +118,13 @@ impl IdtEntry {
         self.offset_middle = (func_ptr >> 16) as u16;
         self.offset_high = (func_ptr >> 32) as u32;
     }
+
+    /// Makes the CPU switch to the stack in IST entry `ist` when delivering this interrupt.
+    ///
+    /// An index of 0 keeps the interrupted stack.
+    fn set_stack_index(&mut self, ist: u8) {
+        self.ist = ist;
+    }
 }
 
 pub fn init() {
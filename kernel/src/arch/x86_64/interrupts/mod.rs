@@ -1,9 +1,15 @@
 use core::arch::asm;
 
+pub mod dispatch;
 pub mod exceptions;
-mod handler;
+pub mod handler;
 pub mod idt;
 
+pub use dispatch::{hits, register, unregister};
+
+/// `RFLAGS.IF`: set when interrupts are enabled.
+const RFLAGS_IF: u64 = 1 << 9;
+
 /// Wrapper around the `cli` instruction to disable interrupts
 pub fn disable_interrupts() {
     // Safety: It is always safe to call `cli`
@@ -12,6 +18,28 @@ pub fn disable_interrupts() {
     }
 }
 
+/// Runs `f` with interrupts disabled, restoring them afterwards if they were enabled.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let rflags: u64;
+    // Safety: Reading RFLAGS has no side effects
+    unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+
+    disable_interrupts();
+    let result = f();
+    if rflags & RFLAGS_IF != 0 {
+        enable_interrupts();
+    }
+    result
+}
+
+/// Wrapper around the `hlt` instruction, waiting for the next interrupt
+pub fn wait_for_interrupt() {
+    // Safety: Halting until the next interrupt has no side effects
+    unsafe {
+        asm!("hlt", options(nomem, nostack));
+    }
+}
+
 /// Wrapper around the `sti` instruction to enable interrupts
 pub fn enable_interrupts() {
     // Safety: It is always safe to call `sti`
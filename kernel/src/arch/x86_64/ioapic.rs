@@ -0,0 +1,314 @@
+//! # I/O APIC
+//!
+//! I/O APICs receive interrupts from devices and forward them to local APICs. Each one handles
+//! a range of *global system interrupts* (GSIs), and has a redirection entry per GSI choosing
+//! the vector, destination CPU, polarity and trigger mode it is delivered with.
+//!
+//! The I/O APICs and the ISA IRQs that aren't identity mapped to GSIs are described by the
+//! ACPI MADT. The legacy 8259 PICs are masked, as they would otherwise deliver the same ISA
+//! interrupts a second time.
+
+use alloc::{vec, vec::Vec};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::{
+        interrupts::dispatch::{self, Handler, HandlerId},
+        x86_64::{
+            acpi::{self, InterruptOverride, Polarity, TriggerMode},
+            apic, pic,
+        },
+    },
+    memory::{addr::VirtAddr, vmm},
+};
+
+/// Offset of the register selecting which register the window accesses.
+const IOREGSEL: u64 = 0x00;
+/// Offset of the window onto the selected register.
+const IOWIN: u64 = 0x10;
+/// Size of the register window.
+const MMIO_SIZE: u64 = 0x20;
+
+/// Register holding the version and number of redirection entries.
+const IOAPICVER: u32 = 0x01;
+/// Register holding the low half of the first redirection entry.
+const IOREDTBL: u32 = 0x10;
+
+/// Redirection entry bit selecting an active low polarity.
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+/// Redirection entry bit selecting level triggering.
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+/// Redirection entry bit masking the interrupt.
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Errors that can occur while routing an interrupt.
+#[derive(Debug, Clone, Copy)]
+pub enum IoApicError {
+    /// No I/O APIC handles the global system interrupt.
+    UnknownGsi,
+    /// Every vector available to devices is in use.
+    NoFreeVector,
+    /// The destination APIC ID can't be addressed by an I/O APIC.
+    InvalidDestination,
+    /// The global system interrupt is already routed with a different polarity or trigger mode.
+    ModeConflict,
+    /// The handler isn't registered for the global system interrupt.
+    UnknownHandler,
+}
+
+/// How a global system interrupt is routed.
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    vector: u8,
+    polarity: Polarity,
+    trigger: TriggerMode,
+    /// Number of handlers registered. The GSI is masked when there is none.
+    handlers: usize,
+}
+
+struct IoApic {
+    id: u8,
+    /// Virtual address of the register window.
+    base: VirtAddr,
+    /// First global system interrupt handled.
+    gsi_base: u32,
+    /// Number of redirection entries, i.e. GSIs handled.
+    entries: u32,
+    /// How each GSI is routed, if it ever was. GSIs keep their vector once they have one.
+    routes: Vec<Option<Route>>,
+}
+
+static IO_APICS: Once<Mutex<Vec<IoApic>>> = Once::new();
+static OVERRIDES: Once<Vec<InterruptOverride>> = Once::new();
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        // Safety: The register window is mapped uncached, and the caller holds the I/O APIC lock
+        // so the selected register can't change under us
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(register);
+            (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        // Safety: Same as `read()`
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(register);
+            (self.base + IOWIN)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    /// Writes redirection entry `index`.
+    #[allow(clippy::cast_possible_truncation)] // We explicitly want to split the entry in two halves
+    fn set_redirection(&self, index: u32, entry: u64) {
+        let register = IOREDTBL + index * 2;
+        // Mask the entry while it is half-written
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Discovers the I/O APICs from the MADT, masking all of their interrupts and the legacy PICs.
+///
+/// Must be called after the kernel's virtual address space has been initialized.
+///
+/// # Panics
+///
+/// Panics if an I/O APIC's registers can't be mapped.
+pub fn init() {
+    pic::disable();
+
+    let madt = acpi::madt().unwrap_or_else(|err| {
+        log::warn!("Couldn't read the MADT ({err:?}), device interrupts are unavailable");
+        acpi::Madt {
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        }
+    });
+
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|info| {
+            let region = vmm::kernel_space()
+                .map_mmio(info.address, MMIO_SIZE)
+                .expect("Should be able to map an I/O APIC");
+            let mut io_apic = IoApic {
+                id: info.id,
+                base: region.start(),
+                gsi_base: info.gsi_base,
+                entries: 0,
+                routes: Vec::new(),
+            };
+            io_apic.entries = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+            io_apic.routes = vec![None; io_apic.entries as usize];
+            for index in 0..io_apic.entries {
+                io_apic.set_redirection(index, REDIRECTION_MASKED);
+            }
+
+            log::info!(
+                "I/O APIC {} handles GSIs {}..{}",
+                io_apic.id,
+                io_apic.gsi_base,
+                io_apic.gsi_base + io_apic.entries
+            );
+            io_apic
+        })
+        .collect();
+
+    for source in &madt.overrides {
+        log::debug!("Interrupt source override: {source:?}");
+    }
+
+    IO_APICS.call_once(|| Mutex::new(io_apics));
+    OVERRIDES.call_once(|| madt.overrides);
+}
+
+/// Returns `true` if at least one I/O APIC was found.
+pub fn is_available() -> bool {
+    IO_APICS
+        .get()
+        .is_some_and(|io_apics| !io_apics.lock().is_empty())
+}
+
+/// Returns the global system interrupt ISA IRQ `irq` is delivered on.
+pub fn isa_gsi(irq: u8) -> u32 {
+    overrides()
+        .iter()
+        .find(|source| source.irq == irq)
+        .map_or(u32::from(irq), |source| source.gsi)
+}
+
+fn overrides() -> &'static [InterruptOverride] {
+    OVERRIDES.get().expect("I/O APICs are initialized")
+}
+
+/// Returns the polarity and trigger mode `gsi` is wired with, as far as the MADT tells.
+///
+/// ISA interrupts use the MADT's overrides, or the ISA defaults. Other GSIs are assumed to be
+/// active low and level triggered, like PCI interrupts.
+pub fn default_mode(gsi: u32) -> (Polarity, TriggerMode) {
+    match overrides().iter().find(|source| source.gsi == gsi) {
+        Some(source) => (source.polarity, source.trigger),
+        None if gsi < 16 => (Polarity::ActiveHigh, TriggerMode::Edge),
+        None => (Polarity::ActiveLow, TriggerMode::Level),
+    }
+}
+
+/// Routes global system interrupt `gsi` to `handler` on the CPU with local APIC ID `cpu`,
+/// with the given `polarity` and `trigger` mode.
+///
+/// The first handler registered for a GSI gets a vector of its own, and the end of the interrupt
+/// is signalled to the local APIC once the handlers have run. Later handlers are chained on the
+/// same vector, and keep the destination of the first one. Use [`default_mode()`] for devices
+/// whose wiring is only described by the MADT.
+///
+/// Handlers are unregistered with [`unregister_irq()`].
+///
+/// # Errors
+///
+/// - [`IoApicError::UnknownGsi`] if no I/O APIC handles `gsi`.
+/// - [`IoApicError::NoFreeVector`] if no vector is available.
+/// - [`IoApicError::InvalidDestination`] if `cpu` doesn't fit in a redirection entry.
+/// - [`IoApicError::ModeConflict`] if `gsi` is already routed with another polarity or trigger mode.
+pub fn register_irq(
+    gsi: u32,
+    handler: Handler,
+    cpu: u32,
+    polarity: Polarity,
+    trigger: TriggerMode,
+) -> Result<HandlerId, IoApicError> {
+    let destination = u8::try_from(cpu).map_err(|_| IoApicError::InvalidDestination)?;
+
+    let mut io_apics = IO_APICS.get().expect("I/O APICs are initialized").lock();
+    let io_apic = io_apics
+        .iter_mut()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IoApicError::UnknownGsi)?;
+    let index = gsi - io_apic.gsi_base;
+
+    let route = &mut io_apic.routes[index as usize];
+    if let Some(route) = route.as_mut().filter(|route| route.handlers > 0) {
+        if route.polarity != polarity || route.trigger != trigger {
+            return Err(IoApicError::ModeConflict);
+        }
+        route.handlers += 1;
+        return Ok(
+            dispatch::register(route.vector, handler).expect("Device vectors aren't exceptions")
+        );
+    }
+
+    // A GSI whose handlers were all unregistered gets its masked vector back
+    let id = match route {
+        Some(route) => {
+            dispatch::register(route.vector, handler).expect("Device vectors aren't exceptions")
+        }
+        None => dispatch::allocate_vector(handler).map_err(|_| IoApicError::NoFreeVector)?,
+    };
+    let vector = id.vector();
+    dispatch::set_end_of_interrupt(vector, |_| apic::eoi());
+    *route = Some(Route {
+        vector,
+        polarity,
+        trigger,
+        handlers: 1,
+    });
+
+    // Fixed delivery to a physical destination
+    let mut entry = u64::from(vector) | (u64::from(destination) << 56);
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    io_apic.set_redirection(index, entry);
+
+    log::debug!("Routed GSI {gsi} ({polarity:?}, {trigger:?}) to vector {vector:#x} on APIC {cpu}");
+    Ok(id)
+}
+
+/// Unregisters `handler`, registered for global system interrupt `gsi` with [`register_irq()`].
+///
+/// The GSI is masked once its last handler is unregistered. It keeps its vector, which is used
+/// again by the next handler registered for it.
+///
+/// # Errors
+///
+/// - [`IoApicError::UnknownGsi`] if no I/O APIC handles `gsi`.
+/// - [`IoApicError::UnknownHandler`] if `handler` isn't registered for `gsi`.
+pub fn unregister_irq(gsi: u32, handler: HandlerId) -> Result<(), IoApicError> {
+    let mut io_apics = IO_APICS.get().expect("I/O APICs are initialized").lock();
+    let io_apic = io_apics
+        .iter_mut()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IoApicError::UnknownGsi)?;
+    let index = gsi - io_apic.gsi_base;
+
+    let route = io_apic.routes[index as usize]
+        .as_mut()
+        .filter(|route| route.vector == handler.vector())
+        .ok_or(IoApicError::UnknownHandler)?;
+    dispatch::unregister(handler).map_err(|_| IoApicError::UnknownHandler)?;
+    route.handlers -= 1;
+
+    if route.handlers == 0 {
+        let vector = route.vector;
+        io_apic.set_redirection(index, REDIRECTION_MASKED | u64::from(vector));
+        log::debug!("Masked GSI {gsi}, which has no handler left");
+    }
+    Ok(())
+}
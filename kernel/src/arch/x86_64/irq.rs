@@ -0,0 +1,134 @@
+//! # Device IRQs
+//!
+//! Drivers register handlers for ISA IRQ lines through this module, without caring which
+//! interrupt controller delivers them: the I/O APICs when the CPU has a local APIC and the MADT
+//! describes at least one I/O APIC, or the legacy 8259 PICs otherwise.
+//!
+//! Several handlers can be registered for the same IRQ line, for devices that share it. The
+//! end of the interrupt is signalled to the controller once all of them have run.
+
+use spin::Once;
+
+use crate::arch::{
+    cpuid,
+    interrupts::dispatch::{self, Handler, HandlerId},
+    x86_64::{
+        apic,
+        ioapic::{self, IoApicError},
+        pic,
+    },
+};
+
+/// Errors that can occur while registering an IRQ handler.
+#[derive(Debug, Clone, Copy)]
+pub enum IrqError {
+    /// The IRQ line doesn't exist on the active controller.
+    InvalidIrq,
+    /// No vector is available for the handler.
+    NoFreeVector,
+    /// The calling CPU can't receive the IRQ.
+    InvalidDestination,
+    /// The IRQ line is already routed with a different polarity or trigger mode.
+    ModeConflict,
+    /// The handler isn't registered for the IRQ line.
+    UnknownHandler,
+}
+
+impl From<IoApicError> for IrqError {
+    fn from(err: IoApicError) -> Self {
+        match err {
+            IoApicError::UnknownGsi => Self::InvalidIrq,
+            IoApicError::NoFreeVector => Self::NoFreeVector,
+            IoApicError::InvalidDestination => Self::InvalidDestination,
+            IoApicError::ModeConflict => Self::ModeConflict,
+            IoApicError::UnknownHandler => Self::UnknownHandler,
+        }
+    }
+}
+
+/// An interrupt controller delivering ISA IRQs.
+pub trait InterruptController: Sync {
+    /// Returns the name of the controller, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Delivers IRQ `irq` to `handler` on the calling CPU, and unmasks it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`IrqError`] if the IRQ can't be routed.
+    fn register_irq(&self, irq: u8, handler: Handler) -> Result<HandlerId, IrqError>;
+}
+
+/// Local APICs, with device IRQs routed through the I/O APICs.
+struct Apic;
+
+impl InterruptController for Apic {
+    fn name(&self) -> &'static str {
+        "I/O APIC"
+    }
+
+    fn register_irq(&self, irq: u8, handler: Handler) -> Result<HandlerId, IrqError> {
+        let gsi = ioapic::isa_gsi(irq);
+        let (polarity, trigger) = ioapic::default_mode(gsi);
+        Ok(ioapic::register_irq(
+            gsi,
+            handler,
+            apic::id(),
+            polarity,
+            trigger,
+        )?)
+    }
+}
+
+/// The legacy 8259 PICs, which only deliver IRQs to the bootstrap processor.
+struct Pic;
+
+impl InterruptController for Pic {
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
+
+    fn register_irq(&self, irq: u8, handler: Handler) -> Result<HandlerId, IrqError> {
+        if irq >= pic::IRQ_LINES {
+            return Err(IrqError::InvalidIrq);
+        }
+
+        let id = dispatch::register(pic::VECTOR_BASE + irq, handler)
+            .expect("PIC vectors aren't exceptions");
+        pic::set_masked(irq, false);
+        Ok(id)
+    }
+}
+
+static CONTROLLER: Once<&'static dyn InterruptController> = Once::new();
+
+fn controller() -> &'static dyn InterruptController {
+    *CONTROLLER.get().expect("IRQ controller is initialized")
+}
+
+/// Chooses the interrupt controller delivering device IRQs.
+///
+/// Must be called after the local and I/O APICs have been initialized, if the CPU has a local
+/// APIC.
+pub fn init() {
+    let controller = CONTROLLER.call_once(|| {
+        if cpuid::has_apic() && ioapic::is_available() {
+            &Apic
+        } else {
+            pic::init();
+            &Pic
+        }
+    });
+    log::info!("Device IRQs delivered by the {}", controller.name());
+}
+
+/// Delivers IRQ `irq` to `handler` on the calling CPU, and unmasks it.
+///
+/// If the IRQ already has handlers, `handler` runs after them, on the CPU they run on.
+///
+/// # Errors
+///
+/// Returns an [`IrqError`] if the IRQ can't be routed.
+pub fn register_irq(irq: u8, handler: Handler) -> Result<HandlerId, IrqError> {
+    controller().register_irq(irq, handler)
+}
@@ -1,20 +1,45 @@
+use core::arch::asm;
+
 use crate::{
-    arch::interrupts::exceptions::register_exceptions, drivers, logger, memory::frame_allocator,
+    arch::interrupts::exceptions::register_exceptions,
+    drivers, logger,
+    memory::{
+        addr::{self, VirtAddr},
+        frame_allocator, frame_meta, heap, paging,
+        stack::KernelStack,
+        vmm,
+    },
+    time,
 };
 
+pub mod acpi;
+pub mod apic;
+pub mod cpuid;
 mod gdt;
 pub mod interrupts;
 pub mod io;
+pub mod ioapic;
+pub mod irq;
+pub mod percpu;
+mod pic;
+pub mod registers;
+mod smp;
+mod syscall;
+pub mod tlb;
+pub mod tss;
+pub mod usermode;
 
 pub use interrupts::{disable_interrupts, enable_interrupts};
 
 #[derive(Debug, Clone, Copy)]
-#[allow(dead_code)] // TODO: Replace this once we start supporting usermode
 enum PrivilegeLevel {
     Kernel = 0,
     User = 3,
 }
 
+/// Number of pages in the stack `kmain` runs on.
+const KMAIN_STACK_PAGES: u64 = 16;
+
 /// Entry point for `x86_64` architecture.
 #[unsafe(no_mangle)]
 pub extern "C" fn x86_64_main() -> ! {
@@ -25,16 +50,71 @@ pub extern "C" fn x86_64_main() -> ! {
     logger::init();
     log::debug!("Serial logger initialized!");
 
-    gdt::init();
+    let tss = gdt::init();
     log::debug!("GDT... OK!");
 
+    percpu::init(tss);
+
     interrupts::idt::init();
+    interrupts::dispatch::init();
     register_exceptions();
+    syscall::init();
+
+    addr::init();
+    drivers::framebuffer::init();
 
     frame_allocator::init();
     log::debug!("Registered memory map and initialized physical frame allocator");
 
-    crate::kmain()
+    frame_allocator::upgrade();
+
+    paging::init();
+    log::debug!("Paging... OK!");
+
+    heap::init();
+    log::debug!("Heap... OK!");
+
+    vmm::init();
+    tss::init_bsp_stacks();
+    frame_meta::init();
+
+    time::init();
+    if cpuid::has_apic() {
+        apic::init();
+        ioapic::init();
+    }
+    tlb::init();
+    irq::init();
+    smp::init();
+
+    // Every bootloader response we need has been copied by now, and the
+    // application processors have left their bootloader-provided stacks
+    frame_allocator::reclaim();
+
+    // Leave Limine's boot stack for one with a guard page
+    let stack = KernelStack::new("kmain", KMAIN_STACK_PAGES)
+        .expect("Should be able to allocate the kmain stack");
+    // Safety: The stack was just allocated, and kmain never returns to free it
+    unsafe { switch_stack(stack.top(), crate::kmain) }
+}
+
+/// Switches to the stack whose top is `top`, and calls `entry` on it.
+///
+/// # Safety
+///
+/// `top` must be the 16-byte aligned top of a mapped stack that nothing else uses.
+unsafe fn switch_stack(top: VirtAddr, entry: fn() -> !) -> ! {
+    // Safety: The caller guarantees the stack is valid, and `entry` never returns to the old one
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "xor rbp, rbp",
+            "call {entry}",
+            top = in(reg) top.as_u64(),
+            entry = in(reg) entry,
+            options(noreturn)
+        );
+    }
 }
 
 /// Halt the CPU indefinitely.
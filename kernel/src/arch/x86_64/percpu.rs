@@ -0,0 +1,110 @@
+//! Per-CPU data, reached through the `GS` segment.
+//!
+//! Each CPU has its own [`PerCpu`] structure, whose address is loaded into `IA32_GS_BASE` while
+//! the CPU runs kernel code. The first field points back to the structure itself, so a single
+//! `gs`-relative load is enough to find it. While user code runs, the kernel's `GS` base is
+//! parked in `IA32_KERNEL_GS_BASE`, and every entry into the kernel from ring 3 swaps it back
+//! in with `swapgs`.
+//!
+//! Use the [`percpu!`](crate::percpu) macro to access a field of the current CPU's data:
+//!
+//! ```rust
+//! let id = percpu!(cpu_id);
+//! percpu!(preempt_count).fetch_add(1, Ordering::Relaxed);
+//! ```
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::arch::{registers, x86_64::tss::TaskStateSegment};
+
+/// Per-CPU data of the bootstrap processor.
+static BSP_DATA: PerCpu = PerCpu::new(0);
+
+/// Data owned by a single CPU.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to this structure, so that it can be found through `gs:[0]`.
+    self_ptr: AtomicPtr<PerCpu>,
+    /// Top of the stack system calls run on.
+    pub(super) kernel_stack: AtomicU64,
+    /// The user stack pointer, while a system call switches stacks.
+    pub(super) user_stack: AtomicU64,
+    /// Identifier of this CPU, 0 being the bootstrap processor.
+    pub cpu_id: u32,
+    /// This CPU's task state segment.
+    pub(super) tss: AtomicPtr<TaskStateSegment>,
+    /// Identifier of the task running on this CPU.
+    #[allow(dead_code)] // TODO: Set by the scheduler once there are tasks
+    pub current_task: AtomicU64,
+    /// Number of nested sections that must not be preempted. Preemption is allowed at zero.
+    #[allow(dead_code)] // TODO: Checked by the scheduler once there are tasks
+    pub preempt_count: AtomicUsize,
+    /// Number of memory manager locks held, see [`crate::memory::lock`].
+    pub mm_locks: AtomicUsize,
+}
+
+impl PerCpu {
+    /// Creates the data of CPU `cpu_id`.
+    pub const fn new(cpu_id: u32) -> Self {
+        Self {
+            self_ptr: AtomicPtr::new(core::ptr::null_mut()),
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            cpu_id,
+            tss: AtomicPtr::new(core::ptr::null_mut()),
+            current_task: AtomicU64::new(0),
+            preempt_count: AtomicUsize::new(0),
+            mm_locks: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Makes `data` the per-CPU data of the calling CPU, whose TSS is `tss`.
+///
+/// # Safety
+///
+/// `data` must not be installed on any other CPU, and the CPU must be running kernel code
+/// with its kernel `GS` base active.
+pub unsafe fn install(data: &'static PerCpu, tss: *mut TaskStateSegment) {
+    let ptr = core::ptr::from_ref(data).cast_mut();
+    data.self_ptr.store(ptr, Ordering::Relaxed);
+    data.tss.store(tss, Ordering::Relaxed);
+
+    // Safety: These MSRs exist on every 64-bit CPU. User code starts with a null GS base
+    unsafe {
+        registers::wrmsr(registers::IA32_GS_BASE, ptr as u64);
+        registers::wrmsr(registers::IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/// Installs the per-CPU data of the bootstrap processor, whose TSS is `tss`.
+pub fn init(tss: *mut TaskStateSegment) {
+    // Safety: This runs once, on the bootstrap processor
+    unsafe { install(&BSP_DATA, tss) };
+}
+
+/// Returns the per-CPU data of the calling CPU.
+///
+/// Using this before the data has been installed page faults on a null pointer.
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    // Safety: In kernel code, the GS base always points to an installed `PerCpu`
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*ptr
+    }
+}
+
+/// Accesses a field of the calling CPU's [`PerCpu`] data.
+///
+/// Evaluates to a reference to the field. Fields that change are atomics, so they can be
+/// updated through the shared reference.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::arch::percpu::current().$field
+    };
+}
@@ -0,0 +1,161 @@
+//! # 8259 PIC
+//!
+//! The legacy pair of chained programmable interrupt controllers, used when there is no usable
+//! APIC. The master handles ISA IRQs 0 to 7, and the slave, cascaded through the master's IRQ 2,
+//! handles IRQs 8 to 15. Both are remapped to vectors [`VECTOR_BASE`] onwards, as their default
+//! vectors overlap with CPU exceptions.
+//!
+//! Each PIC raises its lowest priority IRQ (7 or 15) when an interrupt disappears before it is
+//! acknowledged. Those spurious IRQs aren't marked in-service, and must not be acknowledged.
+
+use spin::Mutex;
+
+use crate::arch::{
+    interrupts::dispatch,
+    io::{inb, outb},
+};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+/// Unused port, written to give the PICs time to process a command.
+const WAIT_PORT: u16 = 0x80;
+
+/// ICW1: starts initialization, with an ICW4 following.
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 mode.
+const ICW4_8086: u8 = 0x01;
+/// OCW3: makes reads from the command port return the in-service register.
+const OCW3_READ_ISR: u8 = 0x0b;
+/// Non-specific end of interrupt command.
+const EOI: u8 = 0x20;
+
+/// IRQ of the master the slave is cascaded through.
+const CASCADE_IRQ: u8 = 2;
+
+/// Vector of IRQ 0. IRQ `n` is delivered on vector `VECTOR_BASE + n`.
+pub const VECTOR_BASE: u8 = 32;
+
+/// Number of IRQ lines across both PICs.
+pub const IRQ_LINES: u8 = 16;
+
+/// Interrupt masks of both PICs, the slave's in the upper byte.
+static MASKS: Mutex<u16> = Mutex::new(0xffff);
+
+fn wait() {
+    // Safety: Nothing listens on this port
+    unsafe { outb(WAIT_PORT, 0) };
+}
+
+#[allow(clippy::cast_possible_truncation)] // We explicitly want to split the masks in two halves
+fn write_masks(masks: u16) {
+    // Safety: Writing the interrupt masks has no other side effect
+    unsafe {
+        outb(MASTER_DATA, masks as u8);
+        outb(SLAVE_DATA, (masks >> 8) as u8);
+    }
+}
+
+/// Remaps both PICs to [`VECTOR_BASE`] onwards with every IRQ masked, except for the cascade.
+fn remap() {
+    // Safety: This is the standard initialization sequence, and leaves every device IRQ masked
+    unsafe {
+        outb(MASTER_COMMAND, ICW1_INIT);
+        wait();
+        outb(SLAVE_COMMAND, ICW1_INIT);
+        wait();
+        // ICW2: vector offsets
+        outb(MASTER_DATA, VECTOR_BASE);
+        wait();
+        outb(SLAVE_DATA, VECTOR_BASE + 8);
+        wait();
+        // ICW3: the slave is on the master's IRQ 2, and has cascade identity 2
+        outb(MASTER_DATA, 1 << CASCADE_IRQ);
+        wait();
+        outb(SLAVE_DATA, CASCADE_IRQ);
+        wait();
+        outb(MASTER_DATA, ICW4_8086);
+        wait();
+        outb(SLAVE_DATA, ICW4_8086);
+        wait();
+
+        // Spurious IRQs are told apart from real ones through the in-service register
+        outb(MASTER_COMMAND, OCW3_READ_ISR);
+        outb(SLAVE_COMMAND, OCW3_READ_ISR);
+    }
+
+    let mut masks = MASKS.lock();
+    *masks = !(1 << CASCADE_IRQ);
+    write_masks(*masks);
+}
+
+/// Acknowledges IRQs that are delivered on `vector`.
+fn end_of_vector(vector: u8) {
+    end_of_interrupt(vector - VECTOR_BASE);
+}
+
+/// Initializes the PICs as the active interrupt controller, with every IRQ masked.
+pub fn init() {
+    remap();
+    for irq in 0..IRQ_LINES {
+        dispatch::set_end_of_interrupt(VECTOR_BASE + irq, end_of_vector);
+    }
+    log::info!(
+        "8259 PICs remapped to vectors {VECTOR_BASE}..{}",
+        VECTOR_BASE + IRQ_LINES
+    );
+}
+
+/// Masks every IRQ of the PICs, for when the APICs are used instead.
+///
+/// The PICs are still remapped, so that spurious IRQs they raise don't look like exceptions.
+pub fn disable() {
+    remap();
+    let mut masks = MASKS.lock();
+    *masks = 0xffff;
+    write_masks(*masks);
+
+    // Masked IRQs can still show up as spurious IRQs, whose vectors must be claimed
+    for irq in [7, 15] {
+        dispatch::set_end_of_interrupt(VECTOR_BASE + irq, end_of_vector);
+    }
+}
+
+/// Masks or unmasks IRQ `irq`. Masked IRQs aren't delivered.
+pub fn set_masked(irq: u8, masked: bool) {
+    let mut masks = MASKS.lock();
+    if masked {
+        *masks |= 1 << irq;
+    } else {
+        *masks &= !(1 << irq);
+    }
+    write_masks(*masks);
+}
+
+/// Returns the in-service registers of both PICs, the slave's in the upper byte.
+fn in_service() -> u16 {
+    // Safety: The command ports were set to return the in-service registers by `remap()`
+    unsafe { u16::from(inb(MASTER_COMMAND)) | (u16::from(inb(SLAVE_COMMAND)) << 8) }
+}
+
+/// Signals the end of IRQ `irq` to the PICs, unless it was spurious.
+fn end_of_interrupt(irq: u8) {
+    if matches!(irq, 7 | 15) && in_service() & (1 << irq) == 0 {
+        // The master doesn't know the slave's IRQ was spurious, so it still expects an EOI
+        if irq == 15 {
+            // Safety: Acknowledging the cascade IRQ has no other side effect
+            unsafe { outb(MASTER_COMMAND, EOI) };
+        }
+        return;
+    }
+
+    // Safety: Acknowledging the IRQ being handled has no other side effect
+    unsafe {
+        if irq >= 8 {
+            outb(SLAVE_COMMAND, EOI);
+        }
+        outb(MASTER_COMMAND, EOI);
+    }
+}
@@ -0,0 +1,125 @@
+//! Access to `x86_64` control and model-specific registers.
+
+use core::arch::asm;
+
+use crate::{
+    arch::cpuid,
+    memory::addr::{PhysAddr, VirtAddr},
+};
+
+/// Returns the physical address of the active level 4 page table, as stored in `CR3`.
+///
+/// The lower 12 bits of `CR3` (PCID / cache control flags) are masked off.
+pub fn read_cr3() -> PhysAddr {
+    let value: u64;
+    // Safety: Reading CR3 has no side effects
+    unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    PhysAddr::new(value & !0xfff)
+}
+
+/// Returns the address that caused the last page fault, as stored in `CR2`.
+pub fn read_cr2() -> VirtAddr {
+    let value: u64;
+    // Safety: Reading CR2 has no side effects
+    unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    VirtAddr::new_truncate(value)
+}
+
+/// Returns the current value of the stack pointer.
+pub fn read_rsp() -> VirtAddr {
+    let value: u64;
+    // Safety: Reading RSP has no side effects
+    unsafe { asm!("mov {}, rsp", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    VirtAddr::new(value)
+}
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    // Safety: Reading the TSC has no side effects
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Loads a new level 4 page table into `CR3`, flushing all non-global TLB entries.
+///
+/// # Safety
+///
+/// `addr` must point to a valid level 4 page table that maps the currently executing code,
+/// the current stack, and every other piece of memory the kernel is about to access.
+pub unsafe fn write_cr3(addr: PhysAddr) {
+    unsafe { asm!("mov cr3, {}", in(reg) addr.as_u64(), options(nostack, preserves_flags)) };
+}
+
+/// Reads the model-specific register `msr`.
+///
+/// # Safety
+///
+/// `msr` must be a valid MSR on this CPU, otherwise a general protection fault is raised.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Writes `value` to the model-specific register `msr`.
+///
+/// # Safety
+///
+/// `msr` must be a valid MSR on this CPU, and writing `value` to it must not break any
+/// assumption the kernel relies on.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    #[allow(clippy::cast_possible_truncation)]
+    // We explicitly want to split the value in two halves
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+    }
+}
+
+/// Extended Feature Enable Register.
+pub const IA32_EFER: u32 = 0xc000_0080;
+
+/// Base address of the `GS` segment.
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// `GS` base swapped in by `swapgs`.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// `EFER.NXE`: enables the no-execute bit in page table entries.
+const EFER_NXE: u64 = 1 << 11;
+
+/// `CR0.WP`: makes read-only pages read-only for ring 0 as well.
+const CR0_WP: u64 = 1 << 16;
+
+/// Enables the no-execute page table bit if the CPU supports it.
+///
+/// Returns `true` if no-execute is available.
+pub fn enable_nx() -> bool {
+    if !cpuid::has_nx() {
+        return false;
+    }
+
+    // Safety: EFER exists on every 64-bit CPU, and NXE is supported as checked above
+    unsafe { wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE) };
+    true
+}
+
+/// Enables write protection for supervisor-mode accesses to read-only pages.
+pub fn enable_write_protect() {
+    // Safety: Setting CR0.WP only makes the CPU stricter about writes to read-only pages
+    unsafe {
+        asm!(
+            "mov {tmp}, cr0",
+            "or {tmp}, {wp}",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            wp = in(reg) CR0_WP,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Symmetric multiprocessing bring-up.
+//!
+//! Limine starts every application processor (AP) and parks it, waiting for an entry point to
+//! be written to its [`Cpu`] structure. The bootstrap processor allocates each AP's per-CPU
+//! data and TSS, hands them over through [`Cpu::extra`] and starts the AP at [`ap_entry`].
+//! The AP switches over to the kernel's page tables and loads the kernel's IDT and its per-CPU
+//! data before touching the heap, so that any fault is handled by the kernel. It then loads its
+//! own GDT and TSS, and parks on a kernel stack, taking part in TLB shootdowns.
+//!
+//! The [`Cpu`] structures and the stacks Limine starts the APs on are bootloader-reclaimable
+//! memory, so [`init()`] waits for every AP to leave them before returning.
+
+use alloc::{boxed::Box, format};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use limine::mp::Cpu;
+
+use crate::{
+    MP_REQUEST,
+    arch::{
+        interrupts,
+        percpu::{self, PerCpu},
+        registers,
+        x86_64::{apic, gdt, switch_stack, syscall, tlb, tss},
+    },
+    memory::{addr::PhysAddr, paging, stack::KernelStack},
+    percpu,
+};
+
+/// Number of pages in the stack each AP idles on.
+const AP_STACK_PAGES: u64 = 4;
+
+/// Number of CPUs that finished booting, including the bootstrap processor.
+static ONLINE: AtomicU32 = AtomicU32::new(1);
+/// Physical address of the kernel's level 4 page table, which APs load before anything else.
+static LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+/// Starts every application processor, and waits until all of them are online.
+///
+/// Returns the number of CPUs online.
+pub fn init() -> u32 {
+    let Some(response) = MP_REQUEST.get_response() else {
+        log::warn!("Bootloader didn't provide an MP response, only the BSP is online");
+        return 1;
+    };
+
+    // APs can't lock the page table before their per-CPU data is installed
+    LEVEL_4.store(
+        paging::page_table().level_4_addr().as_u64(),
+        Ordering::Relaxed,
+    );
+
+    let bsp = response.bsp_lapic_id();
+    let mut cpus = 1;
+    for cpu in response.cpus().iter().filter(|cpu| cpu.lapic_id != bsp) {
+        log::debug!("Starting CPU {cpus} (local APIC ID {})", cpu.lapic_id);
+        let data = Box::leak(Box::new(PerCpu::new(cpus)));
+        data.tss.store(tss::allocate(cpus), Ordering::Relaxed);
+        cpu.extra
+            .store(core::ptr::from_ref(data) as u64, Ordering::Release);
+        cpu.goto_address.write(ap_entry);
+        cpus += 1;
+    }
+
+    while ONLINE.load(Ordering::Acquire) < cpus {
+        spin_loop();
+    }
+    log::info!("{cpus} CPUs online");
+    cpus
+}
+
+/// Entry point of application processors, running on the stack Limine provides.
+unsafe extern "C" fn ap_entry(cpu: &Cpu) -> ! {
+    // Safety: The BSP stored a pointer to this AP's leaked per-CPU data before starting it
+    let data = unsafe { &*(cpu.extra.load(Ordering::Acquire) as *const PerCpu) };
+    let cpu_id = data.cpu_id;
+    let tss = data.tss.load(Ordering::Relaxed);
+
+    // The kernel's page tables use no-execute pages and rely on write protection
+    registers::enable_nx();
+    registers::enable_write_protect();
+    let level_4 = PhysAddr::new(LEVEL_4.load(Ordering::Relaxed));
+    // Safety: The kernel's page tables map everything the BSP uses, including this AP's stack
+    unsafe { registers::write_cr3(level_4) };
+
+    // Faults must reach the kernel's handlers, with per-CPU data, before anything is allocated
+    interrupts::idt::init();
+    // Safety: The per-CPU data was allocated for this AP only, and is never freed
+    unsafe { percpu::install(data, tss) };
+    gdt::init_ap(tss);
+    syscall::init();
+    apic::init_ap();
+
+    let stack = KernelStack::new(format!("CPU {cpu_id} idle").leak(), AP_STACK_PAGES)
+        .expect("Should be able to allocate an idle stack");
+    // Safety: The stack was just allocated, and the idle loop never returns to free it
+    unsafe { switch_stack(stack.top(), ap_idle) }
+}
+
+/// Idle loop of application processors.
+fn ap_idle() -> ! {
+    log::debug!(
+        "CPU {} online (local APIC ID {})",
+        percpu!(cpu_id),
+        apic::id()
+    );
+    // Once online, the BSP may change mappings this AP has cached
+    tlb::enable_shootdowns();
+    // This AP no longer uses any bootloader-reclaimable memory
+    ONLINE.fetch_add(1, Ordering::Release);
+
+    // TODO: Enter the scheduler's idle loop once there is a scheduler
+    super::enable_interrupts();
+    super::halt()
+}
@@ -0,0 +1,123 @@
+//! `SYSCALL`/`SYSRET` fast system call entry.
+//!
+//! `SYSCALL` doesn't switch stacks, so the entry point uses `swapgs` to reach the
+//! [`PerCpu`] data holding the kernel stack, and saves the user stack pointer there while it
+//! switches. It then builds a frame with the same layout as an [`InterruptStackFrame`], so
+//! system calls can inspect and modify user registers just like interrupt handlers do.
+//!
+//! Arguments are passed in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, the system call number
+//! in `rax`, which also receives the result. `rcx` and `r11` are clobbered by the CPU.
+
+use core::{mem::offset_of, sync::atomic::Ordering};
+
+use crate::{
+    arch::{
+        interrupts::handler::InterruptStackFrame,
+        percpu::PerCpu,
+        registers::{self, IA32_EFER},
+        x86_64::gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR},
+    },
+    memory::addr::VirtAddr,
+    percpu, pop_preserved, pop_scratch, push_preserved, push_scratch, syscall,
+};
+
+/// Segment selectors for `SYSCALL` and `SYSRET`.
+const IA32_STAR: u32 = 0xc000_0081;
+/// Entry point for `SYSCALL`.
+const IA32_LSTAR: u32 = 0xc000_0082;
+/// `RFLAGS` bits cleared by `SYSCALL`.
+const IA32_FMASK: u32 = 0xc000_0084;
+
+/// `EFER.SCE`: enables `SYSCALL` and `SYSRET`.
+const EFER_SCE: u64 = 1;
+
+/// Flags masked on entry: trap, interrupt, direction and alignment check.
+const SYSCALL_FLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+// `SYSRET` loads SS from the base selector + 8 and CS from the base selector + 16
+const _: () = assert!(USER_DATA_SELECTOR.as_u16() & !3 == KERNEL_CODE_SELECTOR.as_u16() + 16);
+const _: () = assert!(USER_CODE_SELECTOR.as_u16() & !3 == KERNEL_CODE_SELECTOR.as_u16() + 24);
+
+/// Sets the stack system calls run on, on the calling CPU.
+///
+/// # Safety
+///
+/// `top` must be the 16-byte aligned top of a mapped kernel stack, which must not be in use
+/// while the CPU runs in ring 3.
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    percpu!(kernel_stack).store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Enables `SYSCALL` and points it at [`syscall_entry`].
+pub fn init() {
+    let star = (u64::from((KERNEL_CODE_SELECTOR.as_u16() + 8) | 3) << 48)
+        | (u64::from(KERNEL_CODE_SELECTOR.as_u16()) << 32);
+
+    // Safety: These MSRs exist on every 64-bit CPU, and the selectors match the GDT
+    unsafe {
+        registers::wrmsr(IA32_STAR, star);
+        registers::wrmsr(IA32_LSTAR, syscall_entry as *const () as u64);
+        registers::wrmsr(IA32_FMASK, SYSCALL_FLAGS_MASK);
+        registers::wrmsr(IA32_EFER, registers::rdmsr(IA32_EFER) | EFER_SCE);
+    }
+}
+
+/// Entry point of `SYSCALL`.
+///
+/// Builds an [`InterruptStackFrame`] on the kernel stack from the user's registers, calls
+/// [`syscall_handler`] with it and returns to the user with `SYSRET`. The return address is
+/// always canonical, as user memory ends a page before the end of the lower half (see
+/// [`USER_END`](crate::memory::user::USER_END)).
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(concat!(
+        "swapgs;",
+        "mov gs:[{user_stack}], rsp;",
+        "mov rsp, gs:[{kernel_stack}];",
+        // The CPU saved the user's RIP in rcx and RFLAGS in r11
+        "push {user_data};",
+        "push qword ptr gs:[{user_stack}];",
+        "push r11;",
+        "push {user_code};",
+        "push rcx;",
+        "push rax\n",
+        push_scratch!(),
+        push_preserved!(),
+        "cld;",
+        "sti;",
+        "mov rdi, rsp;",
+        "call {handler}\n",
+        "cli;",
+        pop_preserved!(),
+        pop_scratch!(),
+        "pop rax;",
+        "pop rcx;",
+        // Skip CS, SYSRET loads it from STAR
+        "add rsp, 8;",
+        "pop r11;",
+        "pop rsp;",
+        "swapgs;",
+        "sysretq\n"
+    ),
+    user_stack = const offset_of!(PerCpu, user_stack),
+    kernel_stack = const offset_of!(PerCpu, kernel_stack),
+    user_data = const USER_DATA_SELECTOR.as_u16(),
+    user_code = const USER_CODE_SELECTOR.as_u16(),
+    handler = sym syscall_handler,
+    );
+}
+
+/// Decodes the system call number and arguments from the saved registers, and stores the
+/// result in `rax`.
+extern "C" fn syscall_handler(stack: &mut InterruptStackFrame) {
+    let scratch = &stack.scratch;
+    let args = [
+        scratch.rdi,
+        scratch.rsi,
+        scratch.rdx,
+        scratch.r10,
+        scratch.r8,
+        scratch.r9,
+    ];
+    stack.scratch.rax = syscall::dispatch(scratch.rax, args);
+}
@@ -0,0 +1,171 @@
+//! Translation lookaside buffer management.
+//!
+//! Every CPU runs on the kernel's page tables, so changing or removing a mapping must flush it
+//! from the TLB of every CPU, not just the calling one. [`flush()`] does this with a *shootdown*:
+//! it publishes the address, sends an IPI to every other CPU taking part, and waits for each of
+//! them to invalidate the page and acknowledge it.
+//!
+//! Only one shootdown is in flight at a time. The CPU starting one may hold any lock, like the
+//! page table's, and other CPUs may wait for that lock with interrupts disabled, where the IPI
+//! can't reach them. So CPUs never just spin on the locks held while flushing: they take them
+//! with [`lock()`], which serves the shootdown in flight while waiting, as does a CPU waiting to
+//! start its own shootdown.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::{
+    MP_REQUEST,
+    arch::{
+        interrupts::dispatch,
+        x86_64::{apic, cpuid},
+    },
+    memory::addr::VirtAddr,
+    percpu,
+};
+
+/// State of shootdowns, set up by [`init()`].
+struct Shootdowns {
+    /// Vector of the shootdown IPI.
+    vector: u8,
+    /// Bitmap of the CPUs taking part in shootdowns, indexed by CPU id.
+    active: Box<[AtomicU64]>,
+    /// Bitmap of the CPUs that haven't invalidated the page being shot down yet.
+    pending: Box<[AtomicU64]>,
+}
+
+/// Shootdown state, absent until [`init()`] finds a local APIC.
+static SHOOTDOWNS: Once<Shootdowns> = Once::new();
+
+/// Held by the CPU whose shootdown is in flight.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+/// Address of the page being shot down.
+static ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Invalidates the TLB entry for the page containing `addr` on the calling CPU, using `invlpg`.
+fn flush_local(addr: VirtAddr) {
+    // Safety: Invalidating a TLB entry cannot cause memory unsafety, at worst it costs a page walk
+    unsafe { asm!("invlpg [{}]", in(reg) addr.as_u64(), options(nostack, preserves_flags)) };
+}
+
+/// Returns the word and bit of the calling CPU in CPU bitmaps.
+fn cpu_bit() -> (usize, u64) {
+    let cpu_id = *percpu!(cpu_id);
+    ((cpu_id / u64::BITS) as usize, 1 << (cpu_id % u64::BITS))
+}
+
+/// Invalidates the page of the shootdown in flight, if the calling CPU hasn't done so yet.
+fn serve_pending() {
+    let Some(shootdowns) = SHOOTDOWNS.get() else {
+        return;
+    };
+    let (word, bit) = cpu_bit();
+    let pending = &shootdowns.pending[word];
+    if pending.load(Ordering::Acquire) & bit != 0 {
+        flush_local(VirtAddr::new(ADDRESS.load(Ordering::Relaxed)));
+        pending.fetch_and(!bit, Ordering::Release);
+    }
+}
+
+/// Sets up the shootdown IPI, and makes the bootstrap processor take part in shootdowns.
+///
+/// Must be called after the local APIC has been initialized. Without it, [`flush()`] only
+/// flushes the calling CPU's TLB.
+///
+/// # Panics
+///
+/// Panics if no interrupt vector is free.
+pub fn init() {
+    if !cpuid::has_apic() {
+        return;
+    }
+
+    SHOOTDOWNS.call_once(|| {
+        let id = dispatch::allocate_vector(Box::new(|_| serve_pending()))
+            .expect("A vector should be free for TLB shootdowns");
+        dispatch::set_end_of_interrupt(id.vector(), |_| apic::eoi());
+
+        // CPU ids are handed out in order, one per CPU the bootloader reports
+        let cpus = MP_REQUEST
+            .get_response()
+            .map_or(1, |response| response.cpus().len());
+        let bitmap = || {
+            (0..cpus.div_ceil(u64::BITS as usize))
+                .map(|_| AtomicU64::new(0))
+                .collect::<Vec<_>>()
+                .into_boxed_slice()
+        };
+        Shootdowns {
+            vector: id.vector(),
+            active: bitmap(),
+            pending: bitmap(),
+        }
+    });
+    enable_shootdowns();
+}
+
+/// Makes the calling CPU take part in shootdowns.
+///
+/// Must be called on every CPU before it enables interrupts, and before anything relies on it
+/// being online.
+pub fn enable_shootdowns() {
+    if let Some(shootdowns) = SHOOTDOWNS.get() {
+        let (word, bit) = cpu_bit();
+        shootdowns.active[word].fetch_or(bit, Ordering::AcqRel);
+    }
+}
+
+/// Locks `mutex`, serving the shootdown in flight while waiting for it.
+///
+/// Use this for every lock that may be held while calling [`flush()`].
+pub fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        serve_pending();
+        spin_loop();
+    }
+}
+
+/// Invalidates the TLB entry for the page containing `addr` on every CPU.
+pub fn flush(addr: VirtAddr) {
+    flush_local(addr);
+
+    let Some(shootdowns) = SHOOTDOWNS.get() else {
+        return;
+    };
+    let (own_word, own_bit) = cpu_bit();
+    let others = |word: usize| {
+        let active = shootdowns.active[word].load(Ordering::Acquire);
+        if word == own_word {
+            active & !own_bit
+        } else {
+            active
+        }
+    };
+    if (0..shootdowns.active.len()).all(|word| others(word) == 0) {
+        return;
+    }
+
+    let _guard = lock(&SHOOTDOWN);
+
+    ADDRESS.store(addr.as_u64(), Ordering::Relaxed);
+    for (word, pending) in shootdowns.pending.iter().enumerate() {
+        pending.store(others(word), Ordering::Release);
+    }
+    apic::send_ipi_all_excluding_self(shootdowns.vector);
+    while shootdowns
+        .pending
+        .iter()
+        .any(|pending| pending.load(Ordering::Acquire) != 0)
+    {
+        spin_loop();
+    }
+}
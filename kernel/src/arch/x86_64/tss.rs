@@ -0,0 +1,157 @@
+//! The 64-bit Task State Segment.
+//!
+//! In long mode the TSS no longer stores task state. It only holds the stack pointers the CPU
+//! switches to when handling an interrupt: the *Interrupt Stack Table* (IST) lets individual
+//! IDT entries run on a known good stack, no matter what state the interrupted stack is in.
+
+use alloc::{boxed::Box, format};
+use core::sync::atomic::Ordering;
+
+use crate::{
+    arch::x86_64::syscall,
+    memory::{addr::VirtAddr, stack::KernelStack},
+    percpu,
+};
+
+/// IST index of the stack double faults are handled on.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// IST index of the stack non-maskable interrupts are handled on.
+pub const NMI_IST: u8 = 2;
+/// IST index of the stack machine checks are handled on.
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// Number of IST entries that have a dedicated stack.
+const IST_STACKS: usize = 3;
+
+/// Size in bytes of each IST stack.
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+// The bootstrap processor's IST stacks live in `.bss`, so that they're usable before the
+// kernel's virtual address space is set up. Each one is only ever used by a single IST entry,
+// as NMIs and machine checks can interrupt any other handler, including each other.
+// They have no guard pages between them, so they're replaced by `init_bsp_stacks()` as soon as
+// guard-paged stacks can be allocated.
+static mut BSP_STACKS: [IstStack; IST_STACKS] =
+    [const { IstStack([0; IST_STACK_SIZE]) }; IST_STACKS];
+
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStateSegment {
+    reserved_1: u32,
+    privilege_stacks: [u64; 3],
+    reserved_2: u64,
+    interrupt_stacks: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        Self {
+            reserved_1: 0,
+            privilege_stacks: [0; 3],
+            reserved_2: 0,
+            interrupt_stacks: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            // An I/O map base past the end of the segment means there is no I/O permission bitmap
+            #[allow(clippy::cast_possible_truncation)] // The TSS is 104 bytes
+            iomap_base: size_of::<Self>() as u16,
+        }
+    }
+
+    /// Sets the stack the CPU switches to for interrupts using IST entry `index`.
+    fn set_interrupt_stack(&mut self, index: u8, top: VirtAddr) {
+        assert!((1..=7).contains(&index), "IST indices range from 1 to 7");
+        self.interrupt_stacks[usize::from(index - 1)] = top.as_u64();
+    }
+}
+
+/// Sets the stack the CPU switches to when entering the kernel from ring 3, through an
+/// interrupt or a system call, on the calling CPU.
+///
+/// This must be updated whenever switching to a task that runs in user mode, so that its
+/// interrupts, exceptions and system calls are handled on its own kernel stack.
+///
+/// # Safety
+///
+/// `top` must be the 16-byte aligned top of a mapped kernel stack, which must not be in use
+/// while the CPU runs in ring 3.
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    let tss = percpu!(tss).load(Ordering::Relaxed);
+    // Safety: Every CPU's TSS stays allocated, and only the CPU itself modifies it. The CPU only
+    // reads the TSS while delivering an interrupt, and a single aligned store can't be observed
+    // half-written
+    unsafe { (*tss).privilege_stacks[0] = top.as_u64() };
+    // Safety: Same requirements as above
+    unsafe { syscall::set_kernel_stack(top) };
+}
+
+/// Sets up the bootstrap processor's TSS, with its IST entries pointing at the stacks in `.bss`.
+///
+/// # Safety
+///
+/// Must only be called once.
+pub unsafe fn init_bsp() -> *mut TaskStateSegment {
+    let tss = &raw mut BSP_TSS;
+    for (i, index) in [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST]
+        .into_iter()
+        .enumerate()
+    {
+        // Stacks grow downwards, so the top of stack `i` is where stack `i + 1` starts
+        let top = VirtAddr::new((&raw const BSP_STACKS) as u64 + ((i + 1) * IST_STACK_SIZE) as u64);
+        // Safety: The TSS isn't loaded yet, and the caller guarantees nothing else accesses it
+        unsafe { (*tss).set_interrupt_stack(index, top) };
+    }
+    tss
+}
+
+/// Moves the bootstrap processor's IST entries from the `.bss` stacks to guard-paged stacks.
+///
+/// Must be called on the bootstrap processor, once the kernel's virtual address space is initialized.
+///
+/// # Panics
+///
+/// Panics if the stacks can't be allocated.
+pub fn init_bsp_stacks() {
+    let tss = percpu!(tss).load(Ordering::Relaxed);
+    assert_eq!(
+        tss, &raw mut BSP_TSS,
+        "Must be called on the bootstrap processor"
+    );
+
+    // Safety: Only the CPU itself modifies its TSS, and the CPU only reads IST entries while
+    // delivering an interrupt, which can't observe a single aligned store half-written
+    unsafe { allocate_stacks(&mut *tss, *percpu!(cpu_id)) };
+}
+
+/// Allocates the TSS of application processor `cpu_id`, along with guard-paged IST stacks.
+///
+/// # Panics
+///
+/// Panics if the stacks can't be allocated.
+pub fn allocate(cpu_id: u32) -> *mut TaskStateSegment {
+    let mut tss = Box::new(TaskStateSegment::new());
+    allocate_stacks(&mut tss, cpu_id);
+    Box::into_raw(tss)
+}
+
+/// Points every IST entry of `tss` at a new guard-paged stack for CPU `cpu_id`.
+fn allocate_stacks(tss: &mut TaskStateSegment, cpu_id: u32) {
+    for (index, kind) in [
+        (DOUBLE_FAULT_IST, "double fault"),
+        (NMI_IST, "NMI"),
+        (MACHINE_CHECK_IST, "machine check"),
+    ] {
+        let name = format!("CPU {cpu_id} {kind} handler").leak();
+        let stack = KernelStack::new(name, (IST_STACK_SIZE / 4096) as u64)
+            .expect("Should be able to allocate an IST stack");
+        tss.set_interrupt_stack(index, stack.top());
+    }
+}
@@ -0,0 +1,46 @@
+//! Transitions into ring 3.
+
+use core::arch::asm;
+
+use crate::{
+    arch::x86_64::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR},
+    memory::addr::VirtAddr,
+};
+
+/// `RFLAGS` user code starts with: only the interrupt flag and the always-set reserved bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+/// Drops to ring 3, starting execution at `entry` with the stack pointer set to `stack`.
+///
+/// Interrupts are enabled once user code runs, and are handled on the stack set with
+/// [`tss::set_kernel_stack()`](super::tss::set_kernel_stack).
+///
+/// # Safety
+///
+/// - `entry` must point to code mapped executable and user accessible.
+/// - `stack` must be the 16-byte aligned top of a writable, user accessible stack.
+/// - A kernel stack for ring 3 interrupts must have been set.
+pub unsafe fn enter_usermode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    // Safety: The caller guarantees the entry point and stacks are valid. `iretq` pops the
+    // user segments, stack, flags and entry point from the frame built here
+    unsafe {
+        asm!(
+            // The kernel's GS base is kept aside for `swapgs` on the way back in
+            "swapgs",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "push {data}",
+            "push {stack}",
+            "push {rflags}",
+            "push {code}",
+            "push {entry}",
+            "iretq",
+            data = in(reg) u64::from(USER_DATA_SELECTOR.as_u16()),
+            code = in(reg) u64::from(USER_CODE_SELECTOR.as_u16()),
+            stack = in(reg) stack.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            entry = in(reg) entry.as_u64(),
+            options(noreturn)
+        );
+    }
+}
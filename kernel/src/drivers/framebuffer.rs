@@ -0,0 +1,84 @@
+//! # Framebuffer
+//!
+//! A linear framebuffer set up by the bootloader.
+//!
+//! The framebuffer's parameters are copied out of the bootloader's response during [`init()`],
+//! so that the response doesn't need to stay around once bootloader memory is reclaimed.
+
+use spin::Once;
+
+use crate::{FRAMEBUFFER_REQUEST, memory::addr::VirtAddr};
+
+static FRAMEBUFFER: Once<Option<Framebuffer>> = Once::new();
+
+/// A linear framebuffer.
+#[derive(Debug)]
+pub struct Framebuffer {
+    addr: VirtAddr,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+}
+
+impl Framebuffer {
+    /// Sets the pixel at (`x`, `y`) to `color`.
+    ///
+    /// Only the lowest `bpp` bits of `color` are written. Pixels outside the framebuffer are ignored.
+    pub fn put_pixel(&self, x: u64, y: u64, color: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let bytes_per_pixel = usize::from(self.bpp / 8);
+        let offset = y * self.pitch + x * bytes_per_pixel as u64;
+        let pixel = (self.addr + offset).as_mut_ptr::<u8>();
+        for (i, byte) in color.to_le_bytes().iter().take(bytes_per_pixel).enumerate() {
+            // Safety: The pixel lies within the framebuffer, which is mapped for the lifetime of the kernel
+            unsafe { pixel.add(i).write_volatile(*byte) };
+        }
+    }
+}
+
+/// Copies the first framebuffer reported by the bootloader.
+///
+/// If no framebuffer is available, [`framebuffer()`] will return `None`.
+/// If the framebuffer has already been initialized, this function does nothing.
+pub fn init() {
+    FRAMEBUFFER.call_once(|| {
+        let Some(framebuffer) = FRAMEBUFFER_REQUEST
+            .get_response()
+            .and_then(|response| response.framebuffers().next())
+        else {
+            log::warn!("No framebuffer available");
+            return None;
+        };
+
+        log::debug!(
+            "Framebuffer: {}x{} ({} bpp) at {:p}",
+            framebuffer.width(),
+            framebuffer.height(),
+            framebuffer.bpp(),
+            framebuffer.addr()
+        );
+        Some(Framebuffer {
+            addr: VirtAddr::new(framebuffer.addr() as u64),
+            width: framebuffer.width(),
+            height: framebuffer.height(),
+            pitch: framebuffer.pitch(),
+            bpp: framebuffer.bpp(),
+        })
+    });
+}
+
+/// Returns the framebuffer, if the bootloader provided one.
+///
+/// # Panics
+///
+/// Panics if [`init()`] has not yet been called.
+pub fn framebuffer() -> Option<&'static Framebuffer> {
+    FRAMEBUFFER
+        .get()
+        .expect("Framebuffer is initialized")
+        .as_ref()
+}
@@ -1,2 +1,3 @@
+pub mod framebuffer;
 pub mod uart_16650;
 pub use uart_16650 as uart;
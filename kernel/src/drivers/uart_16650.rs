@@ -19,6 +19,9 @@ const LINE_STATUS: u8 = 5;
 const COM_1_ADDR: u16 = 0x3f8;
 
 /// Global access to the COM1 serial port.
+///
+/// It is only locked with interrupts disabled, so that its IRQ handler never waits for code it
+/// interrupted.
 static COM_1: Once<Mutex<SerialPort<Initialized>>> = Once::new();
 
 bitflags::bitflags! {
@@ -170,6 +173,14 @@ impl SerialPort<Initialized> {
         }
     }
 
+    /// Reads a received byte, if there is one.
+    fn read_byte(&self) -> Option<u8> {
+        // Safety: The serial port is initialized, and reading the receive buffer only pops a byte
+        self.get_line_status()
+            .contains(LineStatus::DATA_READY)
+            .then(|| unsafe { self.read_reg(TRANSMIT_RECIEVE) })
+    }
+
     fn get_line_status(&self) -> LineStatus {
         LineStatus::from_bits_truncate(unsafe { self.read_reg(LINE_STATUS) })
     }
@@ -211,6 +222,27 @@ pub fn init() {
     COM_1.call_once(|| Mutex::new(com_1));
 }
 
+/// Makes COM1 raise IRQ 4 whenever it receives data.
+///
+/// # Panics
+/// This function will panic if the serial port isn't initialized.
+pub fn enable_receive_interrupt() {
+    let com1 = COM_1.get().expect("COM1 is initialized");
+    arch::interrupts::without_interrupts(|| {
+        // Safety: The port is initialized, and only the "received data available" interrupt is enabled
+        unsafe { com1.lock().write_reg(INTERRUPT_ENABLED, 0x01) };
+    });
+}
+
+/// Reads a byte received by COM1, if there is one.
+///
+/// This can be called from interrupt handlers, which must read every byte received: the
+/// interrupt stays asserted until then, and isn't raised again on an edge triggered line.
+pub fn try_read_byte() -> Option<u8> {
+    let com1 = COM_1.get()?;
+    arch::interrupts::without_interrupts(|| com1.lock().read_byte())
+}
+
 /// Print text to the serial port.
 /// This macro works similarly to the standard `print!` macro,
 /// but sends the output to the COM1 serial port instead.
@@ -251,6 +283,6 @@ macro_rules! serial_println {
 #[doc(hidden)]
 pub fn serial_print_internal(args: fmt::Arguments) {
     if let Some(com1) = COM_1.get() {
-        com1.lock().write_fmt(args).unwrap();
+        arch::interrupts::without_interrupts(|| com1.lock().write_fmt(args).unwrap());
     }
 }
@@ -2,17 +2,37 @@
 #![no_main]
 #![feature(step_trait)]
 #![feature(allocator_api)]
+#![feature(alloc_error_handler)]
 #![warn(clippy::pedantic)]
 
-use dummy_alloc::DummyAllocator;
+use alloc::{boxed::Box, format, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use limine::{
     BaseRevision,
     request::{
-        FramebufferRequest, HhdmRequest, MemoryMapRequest, RequestsEndMarker, RequestsStartMarker,
+        ExecutableAddressRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, MpRequest,
+        RequestsEndMarker, RequestsStartMarker, RsdpRequest,
     },
 };
 
-use crate::memory::frame_allocator::{FrameAllocator, frame_allocator};
+use crate::arch::{
+    apic::{self, TimerMode},
+    interrupts, irq,
+};
+use crate::memory::{
+    addr::VirtAddr,
+    frame_allocator::{Frame, FrameAllocator, FrameSize2M, FrameSize4K, frame_allocator},
+    heap::KernelAllocator,
+    paging::{self, Page, PageTableFlags},
+    slab::{self, SlabCache},
+    stack::KernelStack,
+    vmm,
+};
+use crate::time::{
+    Duration, Instant,
+    hpet::{self, ComparatorMode},
+};
 
 extern crate alloc;
 
@@ -32,6 +52,18 @@ static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 #[unsafe(link_section = ".requests")]
 static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_ADDRESS_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static MP_REQUEST: MpRequest = MpRequest::new();
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
 static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
@@ -40,16 +72,16 @@ static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
 #[unsafe(link_section = ".requests_end_marker")]
 static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
-/// We need the `alloc` crate for the bootstrap allocator which requires a global allocator to be defined.
-/// Since we don't have a heap allocator yet, we just use [`DummyAllocator`](dummy_alloc::DummyAllocator)
-/// for now.
+/// All `alloc` allocations are served by the kernel heap.
 #[global_allocator]
-static GLOBAL_ALLOC: DummyAllocator = DummyAllocator;
+static GLOBAL_ALLOC: KernelAllocator = KernelAllocator;
 
 mod arch;
 mod drivers;
 mod logger;
 mod memory;
+mod syscall;
+mod time;
 
 /// Kernel main function.
 ///
@@ -63,42 +95,427 @@ mod memory;
 pub fn kmain() -> ! {
     log::debug!("Dropped into kmain!");
     assert!(BASE_REVISION.is_supported());
+    log::debug!("Running on CPU {}", percpu!(cpu_id));
 
-    if let Some(framebuffer_response) = FRAMEBUFFER_REQUEST.get_response()
-        && let Some(framebuffer) = framebuffer_response.framebuffers().next()
-    {
+    if let Some(framebuffer) = drivers::framebuffer::framebuffer() {
         for i in 0..100_u64 {
-            // Calculate the pixel offset using the framebuffer information we obtained above.
-            // We skip `i` scanlines (pitch is provided in bytes) and add `i * 4` to skip `i` pixels forward.
-            let pixel_offset = i * framebuffer.pitch() + i * 4;
-
-            // Write 0xFFFFFFFF to the provided pixel offset to fill it white.
-            unsafe {
-                // This is only temp code and we don't really care about clippy lints here
-                #[allow(clippy::pedantic)]
-                framebuffer
-                    .addr()
-                    .add(pixel_offset as usize)
-                    .cast::<u32>()
-                    .write(0xFFFF_FFFF);
-            };
+            framebuffer.put_pixel(i, i, 0xFFFF_FFFF);
         }
     }
 
-    let frame = frame_allocator().allocate_frame();
-    log::info!("Allocated frame {frame:?}");
-    let frame = frame_allocator().allocate_frame();
-    log::info!("Allocated frame {frame:?}");
-    let frame = frame_allocator().allocate_frame();
+    arch::enable_interrupts();
+
+    time_demo();
+    if arch::cpuid::has_apic() {
+        timer_demo();
+    }
+    hpet_demo();
+    serial_irq_demo();
+    frame_allocator_demo();
+    paging_demo();
+    heap_demo();
+    vmm_demo();
+    copy_on_write_demo();
+
+    let heap_stats = memory::heap::heap().stats();
+    log::info!(
+        "Heap: {} of {} KiB used",
+        heap_stats.used_bytes / 1024,
+        heap_stats.mapped_bytes / 1024
+    );
+
+    if let Some(stats) = frame_allocator().stats() {
+        log::info!(
+            "Physical memory: {} of {} frames free ({} KiB)",
+            stats.free_frames,
+            stats.total_frames,
+            stats.free_bytes() / 1024
+        );
+    }
+
+    usermode_demo()
+}
+
+/// Busy-waits on the clock source.
+fn time_demo() {
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(100));
+    log::info!("Slept for {:?} at {:?} since boot", start.elapsed(), start);
+}
+
+/// Waits for local APIC timer interrupts in every mode.
+fn timer_demo() {
+    let wait_for_ticks = |ticks: u64| {
+        let target = apic::timer_ticks() + ticks;
+        while apic::timer_ticks() < target {
+            arch::interrupts::wait_for_interrupt();
+        }
+    };
+
+    for mode in [TimerMode::OneShot, TimerMode::TscDeadline] {
+        match apic::start_timer(mode, 5_000) {
+            Ok(()) => {
+                wait_for_ticks(1);
+                log::info!("{mode:?} timer fired");
+            }
+            Err(err) => log::info!("{mode:?} timer unavailable: {err:?}"),
+        }
+    }
+
+    // Chained after the APIC's own timer handler
+    let periodic_ticks = Arc::new(AtomicUsize::new(0));
+    let counter = periodic_ticks.clone();
+    let handler = interrupts::register(
+        apic::TIMER_VECTOR,
+        Box::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }),
+    )
+    .expect("The timer vector isn't an exception");
+
+    apic::start_timer(TimerMode::Periodic, 10_000).expect("Periodic timers are always supported");
+    let start = Instant::now();
+    wait_for_ticks(10);
+    log::info!(
+        "{} periodic timer ticks took {:?}",
+        periodic_ticks.load(Ordering::Relaxed),
+        start.elapsed()
+    );
+    apic::stop_timer();
+
+    interrupts::unregister(handler).expect("Handler is registered");
+    log::info!(
+        "Timer vector dispatched {} times",
+        interrupts::hits(apic::TIMER_VECTOR)
+    );
+}
+
+/// Waits for interrupts from HPET comparators in both modes.
+fn hpet_demo() {
+    for (mode, period, ticks) in [
+        (ComparatorMode::OneShot, Duration::from_millis(5), 1),
+        (ComparatorMode::Periodic, Duration::from_millis(10), 10),
+    ] {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let handler = Box::new(move |_: &mut _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let start = Instant::now();
+        match hpet::start_timer(mode, period, handler) {
+            Ok(timer) => {
+                while fired.load(Ordering::Relaxed) < ticks {
+                    interrupts::wait_for_interrupt();
+                }
+                timer.stop();
+                log::info!(
+                    "HPET {mode:?} comparator fired {ticks} times in {:?}",
+                    start.elapsed()
+                );
+            }
+            Err(err) => log::info!("HPET {mode:?} comparator unavailable: {err:?}"),
+        }
+    }
+}
+
+/// Number of bytes received on COM1 through its interrupt.
+static SERIAL_BYTES_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+/// ISA IRQ COM1 is wired to.
+const COM1_IRQ: u8 = 4;
+
+/// Routes COM1's interrupt to a handler counting the bytes it receives.
+fn serial_irq_demo() {
+    let handler = Box::new(|_: &mut _| {
+        while drivers::uart::try_read_byte().is_some() {
+            SERIAL_BYTES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    match irq::register_irq(COM1_IRQ, handler) {
+        Ok(id) => {
+            drivers::uart::enable_receive_interrupt();
+            log::info!("COM1 IRQ routed to vector {:#x}", id.vector());
+        }
+        Err(err) => log::warn!("Couldn't route COM1 IRQ: {err:?}"),
+    }
+}
+
+/// Address the ring 3 program is loaded at.
+const USER_CODE: u64 = 0x40_0000;
+/// Top of the ring 3 stack, at the end of the lower half.
+const USER_STACK_TOP: u64 = memory::user::USER_END - 4096;
+/// Size of the ring 3 stack.
+const USER_STACK_SIZE: u64 = 4 * 4096;
+
+/// Runs a tiny program in ring 3, which logs a message and exits through system calls.
+fn usermode_demo() -> ! {
+    const MESSAGE: &[u8] = b"Hello from ring 3!";
+    #[rustfmt::skip]
+    const CODE: [u8; 30] = [
+        0x48, 0x8d, 0x3d, 0x17, 0x00, 0x00, 0x00, // lea rdi, [rip + MESSAGE]
+        0xbe, 0x12, 0x00, 0x00, 0x00,             // mov esi, MESSAGE.len()
+        0xb8, 0x00, 0x00, 0x00, 0x00,             // mov eax, 0 (log)
+        0x0f, 0x05,                               // syscall
+        0x31, 0xff,                               // xor edi, edi
+        0xb8, 0x01, 0x00, 0x00, 0x00,             // mov eax, 1 (exit)
+        0x0f, 0x05,                               // syscall
+        0xeb, 0xfe,                               // jmp $
+    ];
+
+    let kernel_stack =
+        KernelStack::new("init", 4).expect("Should be able to allocate a kernel stack");
+    // Safety: The stack was just allocated, and is only used for interrupts from ring 3
+    unsafe { arch::tss::set_kernel_stack(kernel_stack.top()) };
+
+    let code = VirtAddr::new(USER_CODE);
+    memory::user::map(
+        code,
+        4096,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    )
+    .expect("Should be able to map user code");
+    // Safety: The page was just mapped writable, and is large enough for the program
+    unsafe {
+        let program = code.as_mut_ptr::<u8>();
+        program.copy_from_nonoverlapping(CODE.as_ptr(), CODE.len());
+        program
+            .add(CODE.len())
+            .copy_from_nonoverlapping(MESSAGE.as_ptr(), MESSAGE.len());
+    }
+    paging::page_table()
+        .update_flags(
+            Page::<FrameSize4K>::containing(code),
+            PageTableFlags::PRESENT | PageTableFlags::USER,
+        )
+        .expect("User code is mapped");
+
+    let stack = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    memory::user::map(
+        stack,
+        USER_STACK_SIZE,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+    .expect("Should be able to map a user stack");
+
+    log::info!("Entering user mode at {code:?}");
+    // Safety: The program and its stack are mapped user accessible, and the kernel stack is set
+    unsafe { arch::usermode::enter_usermode(code, VirtAddr::new(USER_STACK_TOP)) }
+}
+
+/// Exercises the frame allocator with frames of every size.
+fn frame_allocator_demo() {
+    let frame: Frame<FrameSize4K> = frame_allocator().allocate_frame().unwrap();
     log::info!("Allocated frame {frame:?}");
-    let frame = frame.unwrap();
 
     let virt = frame.start_addr().as_hhdm();
     log::info!("Frame virtual address in HHDM: {virt:?}");
 
-    arch::enable_interrupts();
+    // Safety: The frame was just allocated and nothing else references it
+    unsafe { frame_allocator().deallocate_frame(frame) }
+        .expect("The buddy allocator can deallocate frames");
+    let block = frame_allocator()
+        .allocate_contiguous(4)
+        .expect("Should be able to allocate 16 contiguous frames");
+    log::info!("Allocated 16 contiguous frames at {block:?}");
+    // Safety: The block was just allocated and nothing else references it
+    unsafe { frame_allocator().deallocate_contiguous(block, 4) }
+        .expect("The block was allocated by the buddy allocator");
 
-    arch::halt()
+    let huge_frame: Frame<FrameSize2M> = frame_allocator()
+        .allocate_frame()
+        .expect("Should be able to allocate a 2 MiB frame");
+    log::info!("Allocated huge frame {huge_frame:?}");
+    let frames = frame_allocator().split_frame(huge_frame);
+    for frame in frames {
+        // Safety: The huge frame was just allocated and nothing else references its frames
+        unsafe { frame_allocator().deallocate_frame(frame) }
+            .expect("The buddy allocator can deallocate frames");
+    }
+}
+
+/// Maps, remaps and unmaps a test page.
+fn paging_demo() {
+    let page = Page::<FrameSize4K>::containing(VirtAddr::new(0xffff_9000_0000_0000));
+    let frame: Frame<FrameSize4K> = frame_allocator().allocate_frame().unwrap();
+    paging::page_table()
+        .map_to(
+            page,
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            &mut *frame_allocator(),
+        )
+        .expect("Should be able to map a test page");
+    paging::page_table()
+        .update_flags(page, PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE)
+        .expect("Test page should be mapped");
+    let addr = page.start_addr() + 0x123;
+    let translation = paging::page_table()
+        .translate(addr)
+        .expect("Test page should be mapped");
+    assert_eq!(translation.addr, frame.start_addr() + addr.page_offset());
+    log::info!(
+        "Mapped {page:?} (PML4 index {}, PDPT index {}, PD index {}, PT index {}) to {:?} ({} byte page, {:?})",
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+        translation.addr,
+        translation.page_size,
+        translation.flags
+    );
+    let frame = paging::page_table()
+        .unmap(page)
+        .expect("Test page should be mapped");
+    // Safety: The frame is no longer mapped anywhere
+    unsafe { frame_allocator().deallocate_frame(frame) }
+        .expect("The buddy allocator can deallocate frames");
+}
+
+/// Allocates a guarded region of kernel virtual memory and checks its guard pages.
+fn vmm_demo() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vmm::kernel_space()
+        .allocate(5 * 4096, flags, true)
+        .expect("Should be able to allocate a virtual region");
+
+    // Safety: The region was just mapped writable and nothing else uses it
+    unsafe {
+        region
+            .start()
+            .as_mut_ptr::<u64>()
+            .write_volatile(0xdead_beef);
+    }
+    // Safety: The word was written just above
+    let word = unsafe { region.start().as_ptr::<u64>().read_volatile() };
+    assert_eq!(word, 0xdead_beef);
+    let guard = region.end();
+    log::info!(
+        "Allocated {} KiB region at {:?}, {guard:?} is a guard page: {}",
+        region.size() / 1024,
+        region.start(),
+        vmm::kernel_space()
+            .region_containing(guard)
+            .is_some_and(|(_, in_guard)| in_guard)
+    );
+
+    // Safety: The region isn't used anymore
+    unsafe { vmm::kernel_space().free(region) }.expect("Region was allocated above");
+
+    let free_before = frame_allocator()
+        .stats()
+        .map_or(0, |stats| stats.free_frames);
+    let lazy = vmm::kernel_space()
+        .allocate_on_demand(64 * 1024 * 1024, flags, true)
+        .expect("Should be able to reserve a demand paged region");
+    for offset in (0..lazy.size()).step_by(16 * 1024 * 1024) {
+        let byte = (lazy.start() + offset).as_mut_ptr::<u8>();
+        // Safety: The region is ours, and touching it maps a zeroed page
+        unsafe {
+            assert_eq!(byte.read_volatile(), 0);
+            byte.write_volatile(0xaa);
+        }
+    }
+    let free_after = frame_allocator()
+        .stats()
+        .map_or(0, |stats| stats.free_frames);
+    log::info!(
+        "Touched 4 pages of a {} MiB demand paged region, using {} frames",
+        lazy.size() / (1024 * 1024),
+        free_before - free_after
+    );
+    // Safety: The region isn't used anymore
+    unsafe { vmm::kernel_space().free(lazy) }.expect("Region was allocated above");
+    log::info!(
+        "Kernel address space: {} GiB free",
+        vmm::kernel_space().free_bytes() / (1024 * 1024 * 1024)
+    );
+}
+
+/// Shares a page copy-on-write, then writes to both mappings.
+fn copy_on_write_demo() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vmm::kernel_space()
+        .allocate(2 * 4096, flags, false)
+        .expect("Should be able to allocate a virtual region");
+    let original = Page::<FrameSize4K>::containing(region.start());
+    let copy = Page::<FrameSize4K>::containing(region.start() + 4096);
+
+    // Make room for the shared mapping
+    let frame = paging::page_table()
+        .unmap(copy)
+        .expect("Region pages are mapped");
+    // Safety: The frame is no longer mapped anywhere
+    unsafe { frame_allocator().deallocate_frame(frame) }
+        .expect("The buddy allocator can deallocate frames");
+
+    let original_ptr = original.start_addr().as_mut_ptr::<u64>();
+    let copy_ptr = copy.start_addr().as_mut_ptr::<u64>();
+    // Safety: The page is mapped writable and belongs to the region
+    unsafe { original_ptr.write_volatile(42) };
+    paging::page_table()
+        .share_copy_on_write(original, copy, &mut *frame_allocator())
+        .expect("Should be able to share the page");
+
+    // Safety: Both pages are mapped, and writing to them copies the shared frame
+    unsafe {
+        assert_eq!(copy_ptr.read_volatile(), 42);
+        copy_ptr.write_volatile(7);
+        assert_eq!(original_ptr.read_volatile(), 42);
+        original_ptr.write_volatile(43);
+    }
+    let table = paging::page_table();
+    let (original_phys, copy_phys) = (
+        table.translate(original.start_addr()).map(|t| t.addr),
+        table.translate(copy.start_addr()).map(|t| t.addr),
+    );
+    drop(table);
+    log::info!("Copy-on-write page {original_phys:?} was copied to {copy_phys:?} on write");
+
+    // Safety: The region isn't used anymore
+    unsafe { vmm::kernel_space().free(region) }.expect("Region was allocated above");
+}
+
+/// Exercises the heap and slab allocators.
+fn heap_demo() {
+    let mut numbers: Vec<u64> = (0..1000).collect();
+    numbers.retain(|n| n % 7 == 0);
+    let boxed = Box::new(numbers.iter().sum::<u64>());
+    let message = format!(
+        "{} multiples of 7 below 1000, summing to {boxed}",
+        numbers.len()
+    );
+    log::info!("Heap test: {message}");
+    drop(numbers);
+    let big = vec![0_u8; 4 * 1024 * 1024];
+    log::info!("Allocated {} byte buffer at {:p}", big.len(), big.as_ptr());
+    drop(big);
+    let cache = SlabCache::<[u64; 5]>::new("demo");
+    let object = cache
+        .allocate([1, 2, 3, 4, 5])
+        .expect("Should be able to allocate a slab object");
+    // Safety: The object was just allocated and initialized
+    log::info!("Slab object at {object:p}: {:?}", unsafe {
+        object.as_ref()
+    });
+    let stats = cache.stats();
+    log::info!(
+        "Slab cache {}: {} byte objects, {} per {} byte slab",
+        cache.name(),
+        stats.object_size,
+        stats.objects_per_slab,
+        stats.slab_size
+    );
+    // Safety: The object isn't used anymore
+    unsafe { cache.deallocate(object) };
+    drop(cache);
+    for (name, stats) in slab::size_class_stats().filter(|(_, stats)| stats.allocations > 0) {
+        log::debug!(
+            "Slab cache {name}: {} allocations, {} in use",
+            stats.allocations,
+            stats.objects_in_use
+        );
+    }
 }
 
 /// Panic handler for the kernel.
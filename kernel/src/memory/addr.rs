@@ -5,20 +5,66 @@
 //! The purpose of these types is to prevent accidental mixing of address spaces,
 //! enforce proper alignment, and provide utilities for memory management.
 
+use core::{
+    iter::Step,
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
+
 use spin::Lazy;
 
-use crate::HHDM_REQUEST;
+use crate::{
+    HHDM_REQUEST,
+    memory::{mem_map::mmap_iter, paging::kernel::HHDM_REGIONS},
+};
 
 /// Represents errors that can occur when manipulating memory addresses.
 #[derive(Debug, Clone, Copy)]
 pub enum AddrError {
     /// The requested alignment is invalid (alignment must be a power of two).
     InvalidAlignment,
+    /// The address is not aligned to the required boundary.
+    NotAligned,
+    /// The virtual address is not canonical (bits 48..64 don't match bit 47).
+    NonCanonical,
 }
 
 static HHDM_OFFSET: Lazy<VirtAddr> =
     Lazy::new(|| VirtAddr::new(HHDM_REQUEST.get_response().unwrap().offset()));
 
+/// Returns `true` if the HHDM maps physical address `addr`, i.e. the page containing it overlaps
+/// one of the memory map entries in [`HHDM_REGIONS`].
+fn in_hhdm(addr: u64) -> bool {
+    let page = addr & !0xfff;
+    mmap_iter()
+        .filter(|entry| HHDM_REGIONS.contains(&entry.entry_type))
+        .any(|entry| entry.base < page + 0x1000 && page < entry.base + entry.length)
+}
+
+/// Reads the HHDM offset from the bootloader's response.
+///
+/// This must be called before bootloader memory is reclaimed, since the response lives there.
+pub fn init() {
+    Lazy::force(&HHDM_OFFSET);
+}
+
+/// Aligns `addr` down to a multiple of `align`.
+fn align_down(addr: u64, align: u64) -> Result<u64, AddrError> {
+    if !align.is_power_of_two() {
+        return Err(AddrError::InvalidAlignment);
+    }
+
+    Ok(addr & !(align - 1))
+}
+
+/// Aligns `addr` up to a multiple of `align`.
+fn align_up(addr: u64, align: u64) -> Result<u64, AddrError> {
+    if !align.is_power_of_two() {
+        return Err(AddrError::InvalidAlignment);
+    }
+
+    Ok(addr.next_multiple_of(align))
+}
+
 /// A type-safe wrapper around a 64-bit **physical memory address**.
 ///
 /// Prevents accidental mixing with virtual addresses or other integers and provides
@@ -32,20 +78,33 @@ impl PhysAddr {
         Self(addr)
     }
 
+    /// Returns the raw `u64` value of this address.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
     /// Aligns the address **down** to the nearest multiple of `align`.
     ///
     /// Returns an error if `align` is not a power-of-two.
     pub fn align_down(self, align: u64) -> Result<Self, AddrError> {
-        if !align.is_power_of_two() {
-            return Err(AddrError::InvalidAlignment);
-        }
+        align_down(self.0, align).map(Self)
+    }
 
-        let mask = align - 1;
-        if self.0 & mask == 0 {
-            Ok(self)
-        } else {
-            Ok(Self((self.0 | mask) + 1))
-        }
+    /// Aligns the address **up** to the nearest multiple of `align`.
+    ///
+    /// Returns an error if `align` is not a power-of-two.
+    pub fn align_up(self, align: u64) -> Result<Self, AddrError> {
+        align_up(self.0, align).map(Self)
+    }
+
+    /// Returns `true` if the address is a multiple of `align`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    pub fn is_aligned(self, align: u64) -> bool {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        self.0.is_multiple_of(align)
     }
 
     /// Convert this physical address to a virtual address in the Higher Half Direct Map (HHDM).
@@ -64,13 +123,201 @@ impl core::fmt::Debug for PhysAddr {
     }
 }
 
+impl Add<u64> for PhysAddr {
+    type Output = Self;
+
+    fn add(self, rhs: u64) -> Self::Output {
+        Self(self.0 + rhs)
+    }
+}
+
+impl AddAssign<u64> for PhysAddr {
+    fn add_assign(&mut self, rhs: u64) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<u64> for PhysAddr {
+    type Output = Self;
+
+    fn sub(self, rhs: u64) -> Self::Output {
+        Self(self.0 - rhs)
+    }
+}
+
+impl SubAssign<u64> for PhysAddr {
+    fn sub_assign(&mut self, rhs: u64) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub for PhysAddr {
+    type Output = u64;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+impl Step for PhysAddr {
+    fn steps_between(start: &Self, end: &Self) -> (usize, Option<usize>) {
+        u64::steps_between(&start.0, &end.0)
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        u64::forward_checked(start.0, count).map(Self)
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        u64::backward_checked(start.0, count).map(Self)
+    }
+
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (addr, overflowed) = u64::forward_overflowing(start.0, count);
+        (Self(addr), overflowed)
+    }
+
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (addr, overflowed) = u64::backward_overflowing(start.0, count);
+        (Self(addr), overflowed)
+    }
+}
+
+/// A type-safe wrapper around a 64-bit **virtual memory address**.
+///
+/// Virtual addresses are always *canonical*: bits 48 to 63 are copies of bit 47,
+/// splitting the address space into a lower and a higher half.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VirtAddr(u64);
 
 impl VirtAddr {
-    // Create a new `VirtAddr` from a raw `u64`.
+    /// Create a new `VirtAddr` from a raw `u64`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addr` is not canonical. Use [`try_new()`](Self::try_new) to handle this case.
     pub fn new(addr: u64) -> Self {
-        Self(addr)
+        Self::try_new(addr).expect("Virtual address should be canonical")
+    }
+
+    /// Create a new `VirtAddr` from a raw `u64`, checking that it is canonical.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AddrError::NonCanonical`] if bits 48..64 of `addr` aren't copies of bit 47.
+    pub fn try_new(addr: u64) -> Result<Self, AddrError> {
+        if Self::new_truncate(addr).0 == addr {
+            Ok(Self(addr))
+        } else {
+            Err(AddrError::NonCanonical)
+        }
+    }
+
+    /// Create a new `VirtAddr` from a raw `u64`, sign-extending bit 47 to make it canonical.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)] // The shifts are meant to sign-extend
+    pub fn new_truncate(addr: u64) -> Self {
+        Self((((addr << 16) as i64) >> 16) as u64)
+    }
+
+    /// Returns the raw `u64` value of this address.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Returns this address as a raw pointer.
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    /// Returns this address as a mutable raw pointer.
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Aligns the address **down** to the nearest multiple of `align`.
+    ///
+    /// Returns an error if `align` is not a power-of-two.
+    pub fn align_down(self, align: u64) -> Result<Self, AddrError> {
+        align_down(self.0, align).map(Self::new_truncate)
+    }
+
+    /// Aligns the address **up** to the nearest multiple of `align`.
+    ///
+    /// # Errors
+    ///
+    /// - [`AddrError::InvalidAlignment`] if `align` is not a power-of-two.
+    /// - [`AddrError::NonCanonical`] if the aligned address is not canonical.
+    pub fn align_up(self, align: u64) -> Result<Self, AddrError> {
+        Self::try_new(align_up(self.0, align)?)
+    }
+
+    /// Returns `true` if the address is a multiple of `align`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    pub fn is_aligned(self, align: u64) -> bool {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        self.0.is_multiple_of(align)
+    }
+
+    /// Returns the index into the page table at `level` (1 = PT, ..., 4 = PML4) used to translate this address.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `level` is not between 1 and 4.
+    pub fn page_table_index(self, level: usize) -> usize {
+        match level {
+            4 => self.p4_index(),
+            3 => self.p3_index(),
+            2 => self.p2_index(),
+            1 => self.p1_index(),
+            _ => panic!("Invalid page table level {level}"),
+        }
+    }
+
+    /// Returns the index into the level 4 page table (PML4).
+    pub fn p4_index(self) -> usize {
+        ((self.0 >> 39) & 0x1ff) as usize
+    }
+
+    /// Returns the index into the level 3 page table (PDPT).
+    pub fn p3_index(self) -> usize {
+        ((self.0 >> 30) & 0x1ff) as usize
+    }
+
+    /// Returns the index into the level 2 page table (PD).
+    pub fn p2_index(self) -> usize {
+        ((self.0 >> 21) & 0x1ff) as usize
+    }
+
+    /// Returns the index into the level 1 page table (PT).
+    pub fn p1_index(self) -> usize {
+        ((self.0 >> 12) & 0x1ff) as usize
+    }
+
+    /// Returns whether this address lies in the higher half, where the kernel lives.
+    pub fn is_higher_half(self) -> bool {
+        self.0 >> 63 == 1
+    }
+
+    /// Returns the offset of this address within its 4 KiB page.
+    pub fn page_offset(self) -> u64 {
+        self.0 & 0xfff
+    }
+
+    /// Returns the physical address this address maps to if it lies in the HHDM.
+    ///
+    /// The HHDM leaves out reserved and bad memory, so addresses in its holes return `None`.
+    /// See [`Self::hhdm_offset()`] for addresses that only follow the HHDM's layout.
+    pub fn to_phys_if_hhdm(self) -> Option<PhysAddr> {
+        self.hhdm_offset().filter(|addr| in_hhdm(addr.0))
+    }
+
+    /// Returns the physical address at this address's offset into the HHDM, whether the HHDM
+    /// maps it or not, or `None` if this address is below the HHDM.
+    pub fn hhdm_offset(self) -> Option<PhysAddr> {
+        self.0.checked_sub(HHDM_OFFSET.0).map(PhysAddr)
     }
 }
 
@@ -81,3 +328,76 @@ impl core::fmt::Debug for VirtAddr {
             .finish()
     }
 }
+
+impl Add<u64> for VirtAddr {
+    type Output = Self;
+
+    /// # Panics
+    ///
+    /// Panics if the result is not canonical.
+    fn add(self, rhs: u64) -> Self::Output {
+        Self::new(self.0 + rhs)
+    }
+}
+
+impl AddAssign<u64> for VirtAddr {
+    fn add_assign(&mut self, rhs: u64) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<u64> for VirtAddr {
+    type Output = Self;
+
+    /// # Panics
+    ///
+    /// Panics if the result is not canonical.
+    fn sub(self, rhs: u64) -> Self::Output {
+        Self::new(self.0 - rhs)
+    }
+}
+
+impl SubAssign<u64> for VirtAddr {
+    fn sub_assign(&mut self, rhs: u64) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub for VirtAddr {
+    type Output = u64;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+/// Stepping never crosses the non-canonical hole between the lower and higher half.
+impl Step for VirtAddr {
+    fn steps_between(start: &Self, end: &Self) -> (usize, Option<usize>) {
+        u64::steps_between(&start.0, &end.0)
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        Self::try_new(u64::forward_checked(start.0, count)?).ok()
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        Self::try_new(u64::backward_checked(start.0, count)?).ok()
+    }
+
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (addr, overflowed) = u64::forward_overflowing(start.0, count);
+        match Self::try_new(addr) {
+            Ok(addr) => (addr, overflowed),
+            Err(_) => (Self::new_truncate(addr), true),
+        }
+    }
+
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (addr, overflowed) = u64::backward_overflowing(start.0, count);
+        match Self::try_new(addr) {
+            Ok(addr) => (addr, overflowed),
+            Err(_) => (Self::new_truncate(addr), true),
+        }
+    }
+}
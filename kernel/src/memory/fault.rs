@@ -0,0 +1,157 @@
+//! # Page Faults
+//!
+//! Architecture-independent handling of page faults.
+//!
+//! When a page fault occurs, the exception handler builds a [`PageFault`] describing it and calls
+//! [`handle()`], which asks every registered *resolver* in turn whether it can deal with the
+//! fault. Resolvers implement policies such as demand paging, copy-on-write or guard-page
+//! detection, and answer with a [`FaultResolution`]:
+//!
+//! - [`FaultResolution::Resolved`]: the fault was fixed and the faulting instruction can be retried.
+//! - [`FaultResolution::NotHandled`]: the fault isn't the resolver's business, the next one is asked.
+//! - [`FaultResolution::Fatal`]: the fault is known to be unrecoverable, and no further resolver is asked.
+//!
+//! If no resolver fixes the fault, the exception handler prints a detailed report and panics.
+
+use spin::Mutex;
+
+use crate::memory::{addr::VirtAddr, paging};
+
+/// Maximum number of resolvers that can be registered.
+const MAX_RESOLVERS: usize = 8;
+
+bitflags::bitflags! {
+    /// The error code pushed by the CPU for a page fault.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct PageFaultErrorCode: u64 {
+        /// The fault was a protection violation on a present page. If clear, the page wasn't present.
+        const PRESENT = 1;
+        /// The faulting access was a write. If clear, it was a read.
+        const WRITE = 1 << 1;
+        /// The faulting access came from ring 3.
+        const USER = 1 << 2;
+        /// A reserved bit was set in one of the page table entries used to translate the address.
+        const RESERVED_BIT = 1 << 3;
+        /// The faulting access was an instruction fetch.
+        const INSTRUCTION_FETCH = 1 << 4;
+        /// The access violated the protection key of the page.
+        const PROTECTION_KEY = 1 << 5;
+        /// The faulting access was a shadow stack access.
+        const SHADOW_STACK = 1 << 6;
+    }
+}
+
+/// Description of a single page fault.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The virtual address whose access caused the fault.
+    pub addr: VirtAddr,
+    /// The decoded error code.
+    pub error_code: PageFaultErrorCode,
+    /// The address of the faulting instruction.
+    pub ip: VirtAddr,
+}
+
+impl PageFault {
+    /// Returns a short human-readable description of the faulting access.
+    pub fn access(&self) -> &'static str {
+        let code = self.error_code;
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            "shadow stack access"
+        } else if code.contains(PageFaultErrorCode::WRITE) {
+            "write"
+        } else {
+            "read"
+        }
+    }
+
+    /// Logs everything known about the fault, including how the address is currently mapped.
+    pub fn report(&self) {
+        let code = self.error_code;
+        log::error!(
+            "Page fault: {} {} {:?} from {:?} ({}), error code {code:?}",
+            if code.contains(PageFaultErrorCode::USER) {
+                "user"
+            } else {
+                "kernel"
+            },
+            self.access(),
+            self.addr,
+            self.ip,
+            if code.contains(PageFaultErrorCode::PRESENT) {
+                "protection violation"
+            } else {
+                "page not present"
+            },
+        );
+
+        match paging::try_page_table().map(|page_table| page_table.translate(self.addr)) {
+            Some(Some(translation)) => log::error!(
+                "Address maps to {:?} ({} byte page, {:?})",
+                translation.addr,
+                translation.page_size,
+                translation.flags
+            ),
+            Some(None) => log::error!("Address is not mapped"),
+            None => log::error!("Page table is locked, can't walk it"),
+        }
+    }
+}
+
+/// The verdict of a [`FaultResolver`] on a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// The fault was resolved, and the faulting instruction can be retried.
+    Resolved,
+    /// The resolver doesn't handle this fault.
+    NotHandled,
+    /// The fault can't be recovered from, for the given reason.
+    Fatal(&'static str),
+}
+
+/// A function trying to resolve a page fault.
+pub type FaultResolver = fn(&PageFault) -> FaultResolution;
+
+/// Registered resolvers, in the order they're asked.
+static RESOLVERS: Mutex<[Option<(&'static str, FaultResolver)>; MAX_RESOLVERS]> =
+    Mutex::new([None; MAX_RESOLVERS]);
+
+/// Registers a resolver named `name`, which is asked after every previously registered one.
+///
+/// # Panics
+///
+/// Panics if more than [`MAX_RESOLVERS`] resolvers are registered.
+pub fn register_resolver(name: &'static str, resolver: FaultResolver) {
+    let mut resolvers = RESOLVERS.lock();
+    let slot = resolvers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many page fault resolvers registered");
+    *slot = Some((name, resolver));
+    log::debug!("Registered page fault resolver {name}");
+}
+
+/// Tries to resolve `fault` with the registered resolvers.
+///
+/// # Errors
+///
+/// Returns the reason the fault couldn't be resolved.
+pub fn handle(fault: &PageFault) -> Result<(), &'static str> {
+    // Copy the resolvers out, so they're free to fault themselves
+    let resolvers = *RESOLVERS.lock();
+
+    for (name, resolver) in resolvers.iter().flatten() {
+        match resolver(fault) {
+            FaultResolution::Resolved => {
+                log::trace!("Page fault at {:?} resolved by {name}", fault.addr);
+                return Ok(());
+            }
+            FaultResolution::NotHandled => {}
+            FaultResolution::Fatal(reason) => return Err(reason),
+        }
+    }
+
+    Err("No resolver could handle the fault")
+}
@@ -0,0 +1,271 @@
+//! # Bitmap Frame Allocator
+//!
+//! A physical frame allocator that tracks every 4 KiB frame between the lowest and highest
+//! [`EntryType::USABLE`] or [`EntryType::BOOTLOADER_RECLAIMABLE`] addresses with a single bit. A set bit means the
+//! frame is in use (or isn't usable memory at all), a clear bit means it is free.
+//!
+//! The bitmap itself lives in physical memory carved out of the first usable region
+//! large enough to hold it, and is accessed through the HHDM. The
+//! [`BuddyFrameAllocator`](super::BuddyFrameAllocator) is built on top of a
+//! [`BitmapFrameAllocator`], reusing its bitmap to find a block's buddy.
+
+use core::ops::Range;
+
+use limine::memory_map::EntryType;
+
+use crate::memory::{
+    addr::PhysAddr,
+    frame_allocator::{
+        Frame, FrameAllocator, FrameAllocatorError, FrameSize, FrameSize4K, FrameStats,
+        bump::BumpFrameAllocator,
+    },
+    mem_map::mmap_iter,
+};
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A bitmap covering a contiguous span of physical frames.
+///
+/// Each bit corresponds to one 4 KiB frame starting at the bitmap's base address.
+/// Bits are **set** for frames that are in use.
+pub struct FrameBitmap {
+    base: u64,
+    frames: usize,
+    words: &'static mut [u64],
+}
+
+impl FrameBitmap {
+    /// Number of bytes of backing storage needed to track `frames` frames.
+    pub const fn storage_size(frames: usize) -> u64 {
+        (frames.div_ceil(BITS_PER_WORD) * size_of::<u64>()) as u64
+    }
+
+    /// Creates a bitmap covering `frames` frames starting at `base`, with every frame marked as used.
+    ///
+    /// # Safety
+    ///
+    /// `storage` must point to at least [`storage_size(frames)`](FrameBitmap::storage_size) bytes of
+    /// physical memory that is mapped in the HHDM and not used by anything else for the lifetime
+    /// of the kernel.
+    pub unsafe fn new(base: PhysAddr, frames: usize, storage: PhysAddr) -> Self {
+        let len = frames.div_ceil(BITS_PER_WORD);
+        // Safety: The caller guarantees `storage` is large enough, HHDM-mapped, and exclusively ours
+        let words = unsafe { core::slice::from_raw_parts_mut(storage.as_hhdm().as_mut_ptr(), len) };
+        words.fill(u64::MAX);
+
+        Self {
+            base: base.as_u64(),
+            frames,
+            words,
+        }
+    }
+
+    /// Creates a bitmap covering every usable and bootloader-reclaimable frame,
+    /// with every frame marked as used.
+    ///
+    /// The bitmap is stored in the first usable region with enough room at or above `first_free`.
+    /// Returns the bitmap along with the physical range holding it, which must never be handed out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no usable memory regions, or if no usable region is
+    /// large enough to hold the bitmap.
+    pub fn for_allocatable_memory(first_free: u64) -> (Self, Range<u64>) {
+        let (start, end) = mmap_iter()
+            .filter(|entry| {
+                entry.entry_type == EntryType::USABLE
+                    || entry.entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+            })
+            .fold((u64::MAX, 0), |(start, end), entry| {
+                (start.min(entry.base), end.max(entry.base + entry.length))
+            });
+        assert!(
+            start < end,
+            "At least one free region of memory should be present"
+        );
+
+        let start = start & !(FrameSize4K::SIZE - 1);
+        let frames = usize::try_from((end - start).div_ceil(FrameSize4K::SIZE))
+            .expect("Physical memory span fits in usize");
+        let storage_size = Self::storage_size(frames).next_multiple_of(FrameSize4K::SIZE);
+
+        // Place the bitmap in the first usable region with enough space that hasn't been handed out yet
+        let storage = mmap_iter()
+            .filter(|entry| entry.entry_type == EntryType::USABLE)
+            .find_map(|entry| {
+                let base = entry
+                    .base
+                    .max(first_free)
+                    .next_multiple_of(FrameSize4K::SIZE);
+                (base + storage_size <= entry.base + entry.length).then_some(base)
+            })
+            .expect("A usable region should be large enough to hold the frame bitmap");
+
+        log::debug!(
+            "Frame bitmap covers {frames} frames from {:?}, stored at {:x} ({storage_size} bytes)",
+            PhysAddr::new(start),
+            storage
+        );
+
+        // Safety: The storage region is usable memory that hasn't been handed out,
+        // and the caller is told to never allocate it
+        let bitmap = unsafe { Self::new(PhysAddr::new(start), frames, PhysAddr::new(storage)) };
+
+        (bitmap, storage..storage + storage_size)
+    }
+
+    /// Returns the bitmap index of the frame containing `addr`, if it is covered by this bitmap.
+    pub fn index_of(&self, addr: PhysAddr) -> Option<usize> {
+        let offset = addr.as_u64().checked_sub(self.base)?;
+        let index = usize::try_from(offset / FrameSize4K::SIZE).ok()?;
+        (index < self.frames).then_some(index)
+    }
+
+    /// Returns the physical address of the frame at `index`.
+    pub fn addr_of(&self, index: usize) -> PhysAddr {
+        PhysAddr::new(self.base + index as u64 * FrameSize4K::SIZE)
+    }
+
+    /// Returns the number of frames covered by this bitmap.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_used(&self, index: usize) -> bool {
+        self.words[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    pub fn set_used(&mut self, index: usize) {
+        self.words[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    pub fn set_free(&mut self, index: usize) {
+        self.words[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    /// Marks `count` frames starting at `index` as used.
+    pub fn set_range_used(&mut self, index: usize, count: usize) {
+        (index..index + count).for_each(|i| self.set_used(i));
+    }
+
+    /// Marks `count` frames starting at `index` as free.
+    pub fn set_range_free(&mut self, index: usize, count: usize) {
+        (index..index + count).for_each(|i| self.set_free(i));
+    }
+
+    /// Finds the first free frame at or after `start`, wrapping around to the beginning of the bitmap.
+    pub fn find_free(&self, start: usize) -> Option<usize> {
+        let start_word = start / BITS_PER_WORD;
+        let words = self.words.len();
+
+        (0..=words)
+            .map(|i| (start_word + i) % words)
+            .find_map(|word| {
+                let bits = self.words[word];
+                (bits != u64::MAX)
+                    .then(|| word * BITS_PER_WORD + bits.trailing_ones() as usize)
+                    .filter(|&index| index < self.frames)
+            })
+    }
+}
+
+/// A physical frame allocator backed by a [`FrameBitmap`].
+///
+/// Allocation scans the bitmap for a clear bit starting from the most recently allocated frame,
+/// and deallocation simply clears the frame's bit again.
+pub struct BitmapFrameAllocator {
+    bitmap: FrameBitmap,
+    next: usize,
+    stats: FrameStats,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates a bitmap allocator taking over from the early-boot `bump` allocator.
+    ///
+    /// Every [`EntryType::USABLE`] frame the bump allocator hasn't handed out yet is marked
+    /// as free, except for the frames used to store the bitmap itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no usable memory regions, or if no usable region is
+    /// large enough to hold the bitmap.
+    pub fn new(bump: &BumpFrameAllocator) -> Self {
+        let first_free = bump.next_free().as_u64();
+        let (bitmap, storage) = FrameBitmap::for_allocatable_memory(first_free);
+
+        let mut allocator = Self {
+            bitmap,
+            next: 0,
+            stats: FrameStats::default(),
+        };
+
+        for entry in mmap_iter().filter(|entry| entry.entry_type == EntryType::USABLE) {
+            let base = entry.base.next_multiple_of(FrameSize4K::SIZE);
+            let end = (entry.base + entry.length) & !(FrameSize4K::SIZE - 1);
+            if base >= end {
+                continue;
+            }
+            allocator.stats.total_frames += usize::try_from((end - base) / FrameSize4K::SIZE)
+                .expect("Region size fits in usize");
+
+            // Skip everything the bump allocator has already handed out, as well as the bitmap itself
+            let base = base.max(first_free);
+            allocator.free_range(base, end.min(storage.start));
+            allocator.free_range(base.max(storage.end), end);
+        }
+
+        allocator
+    }
+
+    /// Marks every frame in `[start, end)` as free.
+    fn free_range(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+
+        let index = self
+            .bitmap
+            .index_of(PhysAddr::new(start))
+            .expect("Usable frames are covered by the bitmap");
+        let count =
+            usize::try_from((end - start) / FrameSize4K::SIZE).expect("Region size fits in usize");
+        self.bitmap.set_range_free(index, count);
+        self.stats.free_frames += count;
+    }
+
+    /// Consumes the allocator, returning its bitmap and frame counters.
+    pub fn into_parts(self) -> (FrameBitmap, FrameStats) {
+        (self.bitmap, self.stats)
+    }
+}
+
+unsafe impl FrameAllocator<FrameSize4K> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame<FrameSize4K>, FrameAllocatorError> {
+        let index = self
+            .bitmap
+            .find_free(self.next)
+            .ok_or(FrameAllocatorError::NoFreeFrames)?;
+
+        self.bitmap.set_used(index);
+        self.next = index;
+        self.stats.free_frames -= 1;
+
+        Frame::containing(self.bitmap.addr_of(index))
+            .map_err(|_| FrameAllocatorError::InvalidFrameSize)
+    }
+
+    unsafe fn deallocate_frame(
+        &mut self,
+        frame: Frame<FrameSize4K>,
+    ) -> Result<(), FrameAllocatorError> {
+        let index = self
+            .bitmap
+            .index_of(frame.start_addr())
+            .expect("Deallocated frame should be managed by this allocator");
+        assert!(self.bitmap.is_used(index), "Double free of {frame:?}");
+
+        self.bitmap.set_free(index);
+        self.stats.free_frames += 1;
+        Ok(())
+    }
+}
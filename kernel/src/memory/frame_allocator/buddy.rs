@@ -0,0 +1,330 @@
+//! # Buddy Frame Allocator
+//!
+//! A buddy-system allocator for physical memory. Memory is managed in blocks of
+//! `2^order` contiguous 4 KiB frames, where every block is aligned to its own size.
+//!
+//! - Allocating a block of a given order takes the smallest free block that fits,
+//!   splitting it in half repeatedly until it has the requested size.
+//! - Freeing a block checks whether its *buddy* (the other half of the block it was split from)
+//!   is also free, and if so merges them back together, repeating as far up as possible.
+//!
+//! Free blocks are kept in intrusive doubly-linked lists, one per order, with the list nodes
+//! stored inside the free memory itself (accessed through the HHDM). The [`FrameBitmap`] taken
+//! over from a [`BitmapFrameAllocator`] records which frames are free, so that a block's buddy can
+//! be checked in constant time.
+
+use crate::memory::{
+    addr::PhysAddr,
+    frame_allocator::{
+        Frame, FrameAllocator, FrameAllocatorError, FrameSize, FrameSize1G, FrameSize4K,
+        FrameStats,
+        bitmap::{BitmapFrameAllocator, FrameBitmap},
+    },
+};
+
+/// The largest supported block order. Blocks of this order span 1 GiB.
+pub const MAX_ORDER: usize = FrameSize1G::ORDER;
+
+/// Statistics for a single block order of the [`BuddyFrameAllocator`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrderStats {
+    /// Number of free blocks of this order.
+    pub free_blocks: usize,
+    /// Number of blocks of this order that have been allocated so far.
+    pub allocations: usize,
+    /// Number of blocks of this order that have been deallocated so far.
+    ///
+    /// Blocks split into 4 KiB frames count as deallocated here, and their frames
+    /// as allocated at order 0, since that's the order they're freed at.
+    pub deallocations: usize,
+}
+
+/// Intrusive list node stored at the start of every free block.
+struct FreeBlock {
+    next: Option<PhysAddr>,
+    prev: Option<PhysAddr>,
+    order: usize,
+}
+
+/// A buddy-system allocator for physically contiguous, naturally aligned runs of frames.
+pub struct BuddyFrameAllocator {
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    free_map: FrameBitmap,
+    order_stats: [OrderStats; MAX_ORDER + 1],
+    stats: FrameStats,
+}
+
+/// Size in bytes of a block of the given order.
+const fn block_size(order: usize) -> u64 {
+    FrameSize4K::SIZE << order
+}
+
+/// Number of frames in a block of the given order.
+const fn block_frames(order: usize) -> usize {
+    1 << order
+}
+
+/// Splits `[start, end)` into the largest naturally aligned blocks possible, in ascending order.
+fn aligned_blocks(mut start: u64, end: u64) -> impl Iterator<Item = (PhysAddr, usize)> {
+    core::iter::from_fn(move || {
+        if start >= end {
+            return None;
+        }
+
+        let mut order =
+            ((start.trailing_zeros() - FrameSize4K::SIZE.trailing_zeros()) as usize).min(MAX_ORDER);
+        while start + block_size(order) > end {
+            order -= 1;
+        }
+
+        let block = (PhysAddr::new(start), order);
+        start += block_size(order);
+        Some(block)
+    })
+}
+
+impl BuddyFrameAllocator {
+    /// Creates a buddy allocator taking over from `bitmap`.
+    ///
+    /// Every frame that is free in the bitmap is added to the free lists, and the bitmap
+    /// becomes the allocator's free map.
+    pub fn new(bitmap: BitmapFrameAllocator) -> Self {
+        let (free_map, stats) = bitmap.into_parts();
+        let mut allocator = Self {
+            free_lists: [None; MAX_ORDER + 1],
+            free_map,
+            order_stats: [OrderStats::default(); MAX_ORDER + 1],
+            stats,
+        };
+
+        // Runs of free frames are maximal, so blocks from different runs can never be buddies.
+        // Blocks are only pushed, as coalescing would read the nodes of frames not yet added.
+        let frames = allocator.free_map.frames();
+        let mut index = 0;
+        while index < frames {
+            if allocator.free_map.is_used(index) {
+                index += 1;
+                continue;
+            }
+
+            let start = index;
+            while index < frames && !allocator.free_map.is_used(index) {
+                index += 1;
+            }
+            let (start, end) = (
+                allocator.free_map.addr_of(start),
+                allocator.free_map.addr_of(index),
+            );
+            for (addr, order) in aligned_blocks(start.as_u64(), end.as_u64()) {
+                allocator.push(addr, order);
+            }
+        }
+
+        allocator
+    }
+
+    /// Hands every whole frame in the physical range `[start, end)` to the allocator.
+    ///
+    /// Returns the number of frames added.
+    ///
+    /// # Safety
+    ///
+    /// - None of the frames in the range may be in use, or already managed by this allocator.
+    /// - The range must be mapped in the HHDM.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range isn't covered by the allocator's free map.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) -> usize {
+        let start = start
+            .align_up(FrameSize4K::SIZE)
+            .expect("Frame size is a power of two");
+        let end = end
+            .align_down(FrameSize4K::SIZE)
+            .expect("Frame size is a power of two");
+        if start >= end {
+            return 0;
+        }
+
+        let frames =
+            usize::try_from((end - start) / FrameSize4K::SIZE).expect("Region size fits in usize");
+        self.stats.total_frames += frames;
+        self.add_range(start.as_u64(), end.as_u64());
+
+        frames
+    }
+
+    /// Adds every frame in `[start, end)` to the free lists, using the largest aligned blocks possible.
+    fn add_range(&mut self, start: u64, end: u64) {
+        for (addr, order) in aligned_blocks(start, end) {
+            self.free_block(addr, order);
+        }
+    }
+
+    /// Returns the current free/used frame counters.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Returns the statistics for blocks of the given order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `order` is greater than [`MAX_ORDER`].
+    pub fn order_stats(&self, order: usize) -> OrderStats {
+        self.order_stats[order]
+    }
+
+    /// Allocates `2^order` physically contiguous frames, aligned to `2^order` frames.
+    ///
+    /// Returns the physical address of the first frame.
+    ///
+    /// # Errors
+    ///
+    /// - [`FrameAllocatorError::InvalidOrder`] if `order` is greater than [`MAX_ORDER`].
+    /// - [`FrameAllocatorError::NoFreeFrames`] if no free block is large enough.
+    pub fn allocate_contiguous(&mut self, order: usize) -> Result<PhysAddr, FrameAllocatorError> {
+        if order > MAX_ORDER {
+            return Err(FrameAllocatorError::InvalidOrder);
+        }
+
+        let mut current = (order..=MAX_ORDER)
+            .find(|&order| self.free_lists[order].is_some())
+            .ok_or(FrameAllocatorError::NoFreeFrames)?;
+        let addr = self.free_lists[current].expect("Free list is not empty");
+        self.remove(addr, current);
+
+        // Split the block, returning the upper halves to the free lists until it's the right size
+        while current > order {
+            current -= 1;
+            self.push(PhysAddr::new(addr.as_u64() + block_size(current)), current);
+        }
+
+        let index = self
+            .free_map
+            .index_of(addr)
+            .expect("Free blocks are covered by the free map");
+        self.free_map.set_range_used(index, block_frames(order));
+        self.stats.free_frames -= block_frames(order);
+        self.order_stats[order].allocations += 1;
+
+        Ok(addr)
+    }
+
+    /// Deallocates a block previously returned by [`allocate_contiguous()`](Self::allocate_contiguous),
+    /// merging it with its buddies where possible.
+    ///
+    /// # Safety
+    ///
+    /// - `addr` and `order` must match a previous call to [`allocate_contiguous()`](Self::allocate_contiguous)
+    ///   on this allocator.
+    /// - None of the frames in the block may still be in use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `order` is greater than [`MAX_ORDER`], or if `addr` is not managed by this allocator.
+    pub unsafe fn deallocate_contiguous(&mut self, addr: PhysAddr, order: usize) {
+        assert!(order <= MAX_ORDER, "Invalid block order {order}");
+        let index = self
+            .free_map
+            .index_of(addr)
+            .expect("Deallocated block should be managed by this allocator");
+        assert!(self.free_map.is_used(index), "Double free of {addr:?}");
+
+        self.order_stats[order].deallocations += 1;
+        self.free_block(addr, order);
+    }
+
+    /// Records that an allocated block of `order` was split into 4 KiB frames,
+    /// which will be deallocated individually.
+    pub fn record_split(&mut self, order: usize) {
+        self.order_stats[order].deallocations += 1;
+        self.order_stats[0].allocations += block_frames(order);
+    }
+
+    /// Marks a block as free and inserts it into the free lists, coalescing it with its buddies.
+    fn free_block(&mut self, mut addr: PhysAddr, mut order: usize) {
+        let index = self
+            .free_map
+            .index_of(addr)
+            .expect("Freed blocks are covered by the free map");
+        self.free_map.set_range_free(index, block_frames(order));
+        self.stats.free_frames += block_frames(order);
+
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_size(order));
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(addr, order);
+    }
+
+    /// Checks whether `addr` is the start of a free block of exactly `order`.
+    fn is_free_block(&self, addr: PhysAddr, order: usize) -> bool {
+        // A free frame at a block-aligned address is always the head of a free block, since a
+        // larger free block containing it would also contain the block we're trying to merge.
+        self.free_map
+            .index_of(addr)
+            .is_some_and(|index| !self.free_map.is_used(index))
+            && Self::node(addr).order == order
+    }
+
+    /// Returns the free list node stored at the start of the free block at `addr`.
+    #[allow(clippy::mut_from_ref)]
+    fn node(addr: PhysAddr) -> &'static mut FreeBlock {
+        // Safety: Only ever called on free blocks, which are owned by the allocator and mapped in the HHDM
+        unsafe { &mut *addr.as_hhdm().as_mut_ptr::<FreeBlock>() }
+    }
+
+    fn push(&mut self, addr: PhysAddr, order: usize) {
+        let head = self.free_lists[order];
+        *Self::node(addr) = FreeBlock {
+            next: head,
+            prev: None,
+            order,
+        };
+
+        if let Some(head) = head {
+            Self::node(head).prev = Some(addr);
+        }
+
+        self.free_lists[order] = Some(addr);
+        self.order_stats[order].free_blocks += 1;
+    }
+
+    fn remove(&mut self, addr: PhysAddr, order: usize) {
+        let node = Self::node(addr);
+
+        match node.prev {
+            Some(prev) => Self::node(prev).next = node.next,
+            None => self.free_lists[order] = node.next,
+        }
+
+        if let Some(next) = node.next {
+            Self::node(next).prev = node.prev;
+        }
+
+        self.order_stats[order].free_blocks -= 1;
+    }
+}
+
+/// Frames of every size are allocated as buddy blocks of the matching order,
+/// so huge frames are always naturally aligned.
+unsafe impl<S: FrameSize> FrameAllocator<S> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame<S>, FrameAllocatorError> {
+        let addr = self.allocate_contiguous(S::ORDER)?;
+        Frame::containing(addr).map_err(|_| FrameAllocatorError::InvalidFrameSize)
+    }
+
+    unsafe fn deallocate_frame(&mut self, frame: Frame<S>) -> Result<(), FrameAllocatorError> {
+        // Safety: The caller guarantees the frame was allocated by this allocator and is no longer in use
+        unsafe { self.deallocate_contiguous(frame.start_addr(), S::ORDER) };
+        Ok(())
+    }
+}
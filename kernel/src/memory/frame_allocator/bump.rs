@@ -0,0 +1,105 @@
+//! # Bump Frame Allocator
+//!
+//! A simple **bump frame allocator** for physical memory using the memory map
+//! provided by the Limine bootloader. It allocates fixed-size frames by incrementally
+//! "bumping" through usable memory regions without tracking freed frames
+//! (i.e. it cannot deallocate).
+//!
+//! This allocator is only used during early boot, before the
+//! [`BitmapFrameAllocator`](super::bitmap::BitmapFrameAllocator) takes over.
+
+use core::marker::PhantomData;
+
+use limine::memory_map::{Entry, EntryType};
+
+use crate::memory::{
+    addr::PhysAddr,
+    frame_allocator::{Frame, FrameAllocator, FrameAllocatorError, FrameSize, FrameSize4K},
+    mem_map::mmap_iter,
+};
+
+/// A simple bump allocator for physical frames.
+///
+/// Allocates frames by linearly advancing through memory regions discovered
+/// in the memory map. When the current region is exhausted, it moves to the next
+/// [`EntryType::USABLE`] region.
+///
+/// This allocator **does not support deallocation**
+pub struct BumpFrameAllocator<S: FrameSize = FrameSize4K> {
+    current_base: u64,
+    current_end: u64,
+    size: PhantomData<S>,
+}
+
+impl<S: FrameSize> BumpFrameAllocator<S> {
+    // Create a new bump frame allocator using the first usable memory region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no usable memory regions are reported by the bootloader.
+    pub fn new() -> Self {
+        // Find the first free entry
+        let first_entry = mmap_iter()
+            .find(|entry| entry.entry_type == EntryType::USABLE)
+            .expect("At least one free region of memory should be present");
+
+        log::debug!(
+            "First free entry {:x?} ({:?} bytes)",
+            first_entry.base,
+            first_entry.length
+        );
+
+        Self {
+            current_base: first_entry.base,
+            current_end: first_entry.base + first_entry.length,
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the address of the next frame this allocator would hand out.
+    ///
+    /// Since the memory map is sorted and regions are consumed in order, every
+    /// usable frame **below** this address has already been allocated, and every
+    /// usable frame at or above it is still free.
+    pub fn next_free(&self) -> PhysAddr {
+        PhysAddr::new(self.current_base)
+    }
+
+    fn find_next(&self) -> Result<Entry, FrameAllocatorError> {
+        mmap_iter()
+            .filter(|entry| entry.base > self.current_end)
+            .find(|entry| entry.entry_type == EntryType::USABLE)
+            .ok_or(FrameAllocatorError::NoFreeFrames)
+    }
+}
+
+unsafe impl<S: FrameSize> FrameAllocator<S> for BumpFrameAllocator<S> {
+    fn allocate_frame(&mut self) -> Result<Frame<S>, FrameAllocatorError> {
+        // First check if there's enough space in the current memory map entry for this frame
+        if self.current_base + S::SIZE <= self.current_end {
+            let addr = PhysAddr::new(self.current_base);
+            self.current_base += S::SIZE;
+            log::debug!("Allocating frame with address {addr:?}");
+            return Frame::containing(addr).map_err(|_| FrameAllocatorError::InvalidFrameSize);
+        }
+
+        // Find next usable entry if current is exhausted
+        let next_entry = self.find_next()?;
+
+        log::debug!(
+            "Next free entry {:x} ({})",
+            next_entry.base,
+            next_entry.length
+        );
+
+        let addr = PhysAddr::new(next_entry.base);
+        self.current_base = next_entry.base + S::SIZE;
+        self.current_end = next_entry.base + next_entry.length;
+
+        Frame::containing(addr).map_err(|_| FrameAllocatorError::InvalidFrameSize)
+    }
+
+    unsafe fn deallocate_frame(&mut self, _frame: Frame<S>) -> Result<(), FrameAllocatorError> {
+        Err(FrameAllocatorError::Unsupported)
+    }
+}
@@ -0,0 +1,469 @@
+//! # Frame Allocator
+//!
+//! This module manages allocation of physical memory frames using the memory map
+//! provided by the Limine bootloader.
+//!
+//! ## Overview
+//!
+//! - Uses [`limine::memory_map`] to discover usable memory regions.
+//! - Allocates frames of a fixed size (default: 4 KiB), as well as 2 MiB and 1 GiB huge frames
+//!   which can be split back into their constituent 4 KiB frames.
+//! - During early boot, frames are handed out by a [`BumpFrameAllocator`], which cannot deallocate.
+//! - Once early boot is done, [`upgrade()`] replaces it with a [`BuddyFrameAllocator`],
+//!   which supports deallocation, physically contiguous multi-frame allocations,
+//!   and keeps track of how many frames are free.
+//!
+//! Submodules:
+//! - [`bump`]: Non-deallocating bump allocator used during early boot.
+//! - [`bitmap`]: Bitmap allocator taking over from the bump allocator, tracking every frame with a bit.
+//! - [`buddy`]: Buddy-system allocator built on the bitmap, used for the rest of the kernel's lifetime.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use crate::memory::frame_allocator;
+//!
+//! fn example() {
+//!     let mut allocator = frame_allocator();
+//!     let frame = allocator.allocate_frame().unwrap();
+//!     log::info!("Allocated frame: {:?}", frame);
+//! }
+//! ```
+
+use core::marker::PhantomData;
+
+use limine::memory_map::EntryType;
+use spin::{Mutex, Once};
+
+use crate::{
+    MEM_MAP_REQUEST,
+    arch::registers,
+    memory::{
+        addr::{AddrError, PhysAddr},
+        lock::{self, MmGuard},
+        mem_map::{self, mmap_iter},
+        paging,
+    },
+};
+
+pub mod bitmap;
+pub mod buddy;
+pub mod bump;
+
+pub use bitmap::BitmapFrameAllocator;
+pub use buddy::BuddyFrameAllocator;
+pub use bump::BumpFrameAllocator;
+
+/// A global singleton holding the [`GlobalFrameAllocator`] wrapped in a [`Mutex`].
+///
+/// Initialized via [`init()`].
+static FRAME_ALLOCATOR: Once<Mutex<GlobalFrameAllocator>> = Once::new();
+
+/// Errors that can occur during frame allocation.
+#[derive(Debug, Clone, Copy)]
+pub enum FrameAllocatorError {
+    /// The frame size is invalid. Frame sizes must be a power of 2 to ensure proper address alignment
+    InvalidFrameSize,
+    /// No more free frames are available
+    NoFreeFrames,
+    /// The requested block order is larger than [`buddy::MAX_ORDER`]
+    InvalidOrder,
+    /// The active allocator does not support this operation
+    Unsupported,
+}
+
+/// Represents a compile-time constant frame size.
+///
+/// Implemented by types such as [`FrameSize4K`]. Used by [`Frame`] and [`FrameAllocator`] to track the size of their frames.
+pub trait FrameSize {
+    /// Size of each frame in bytes.
+    const SIZE: u64;
+    /// Human-readable string describing the frame size (e.g. `"4 KiB"`).
+    const SIZE_STR: &str;
+    /// Number of 4 KiB frames making up a frame of this size, as a power of two.
+    const ORDER: usize = (Self::SIZE / FrameSize4K::SIZE).trailing_zeros() as usize;
+}
+
+/// Marker type for 4 KiB frames (the default page size on `x86_64`).
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSize4K;
+
+impl FrameSize for FrameSize4K {
+    const SIZE: u64 = 4096;
+    const SIZE_STR: &str = "4 KiB";
+}
+
+/// Marker type for 2 MiB huge frames (mapped by a page directory entry on `x86_64`).
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSize2M;
+
+impl FrameSize for FrameSize2M {
+    const SIZE: u64 = 2 * 1024 * 1024;
+    const SIZE_STR: &str = "2 MiB";
+}
+
+/// Marker type for 1 GiB huge frames (mapped by a page directory pointer table entry on `x86_64`).
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSize1G;
+
+impl FrameSize for FrameSize1G {
+    const SIZE: u64 = 1024 * 1024 * 1024;
+    const SIZE_STR: &str = "1 GiB";
+}
+
+/// Represents a single frame of physical memory.
+///
+/// This is a typed handle to a frame of size `S::SIZE`. The [`start_addr()`]
+/// gives its physical address.
+pub struct Frame<S: FrameSize> {
+    start_addr: PhysAddr,
+    size: PhantomData<S>,
+}
+
+impl<S: FrameSize> Frame<S> {
+    /// Creates a frame containing the given physical address, aligning
+    /// it down to the start of its frame boundary.
+    fn containing(addr: PhysAddr) -> Result<Self, AddrError> {
+        Ok(Self {
+            start_addr: addr.align_down(S::SIZE)?,
+            size: PhantomData,
+        })
+    }
+
+    /// Creates a frame starting at the given physical address.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AddrError::NotAligned`] if `addr` is not aligned to `S::SIZE`.
+    pub fn from_start_addr(addr: PhysAddr) -> Result<Self, AddrError> {
+        if !addr.is_aligned(S::SIZE) {
+            return Err(AddrError::NotAligned);
+        }
+
+        Ok(Self {
+            start_addr: addr,
+            size: PhantomData,
+        })
+    }
+
+    // Returns the starting physical address of this frame.
+    pub fn start_addr(self) -> PhysAddr {
+        self.start_addr
+    }
+
+    /// Fills this frame with zeroes, accessing it through the HHDM.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may be using the frame.
+    pub unsafe fn zero(self) {
+        let len = usize::try_from(S::SIZE).expect("Frame size fits in usize");
+        // Safety: The frame is mapped in the HHDM, and the caller guarantees nothing else uses it
+        unsafe {
+            self.start_addr
+                .as_hhdm()
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, len);
+        }
+    }
+
+    /// Copies the contents of `source` into this frame, accessing both through the HHDM.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may be using this frame, and nothing may be writing to `source`.
+    pub unsafe fn copy_from(self, source: Self) {
+        let len = usize::try_from(S::SIZE).expect("Frame size fits in usize");
+        // Safety: Both frames are mapped in the HHDM, and the caller guarantees nothing else uses them
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                source.start_addr.as_hhdm().as_ptr::<u8>(),
+                self.start_addr.as_hhdm().as_mut_ptr::<u8>(),
+                len,
+            );
+        }
+    }
+
+    /// Splits this frame into the 4 KiB frames it is made of, in ascending order.
+    ///
+    /// Frames handed out by the global allocator should be split with
+    /// [`GlobalFrameAllocator::split_frame()`] instead, so that their 4 KiB frames
+    /// can be deallocated individually.
+    pub fn split(self) -> impl Iterator<Item = Frame<FrameSize4K>> {
+        let start = self.start_addr;
+        (0..S::SIZE / FrameSize4K::SIZE).map(move |i| Frame {
+            start_addr: start + i * FrameSize4K::SIZE,
+            size: PhantomData,
+        })
+    }
+}
+
+impl<S: FrameSize> Clone for Frame<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: FrameSize> Copy for Frame<S> {}
+
+impl<S: FrameSize> core::fmt::Debug for Frame<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "Frame[{}]({:?})",
+            S::SIZE_STR,
+            self.start_addr()
+        ))
+    }
+}
+
+/// Generic trait for frame allocators.
+///
+/// # Safety
+///
+/// Implementors must ensure that:
+/// - Frames that are returned by [`allocate_frame()`](FrameAllocator::allocate_frame) must be free and not in use by anything
+/// - Any allocated frame must be valid until deallocated or the allocator is dropped.
+pub unsafe trait FrameAllocator<S: FrameSize = FrameSize4K> {
+    /// Allocate a single frame of size `S::SIZE`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`FrameAllocatorError::NoFreeFrames`] if all free memory has been exhausted.
+    fn allocate_frame(&mut self) -> Result<Frame<S>, FrameAllocatorError>;
+    /// Deallocate a previously allocated frame.
+    ///
+    /// # Safety
+    ///
+    /// - `frame` must point to a valid frame that was allocated by this allocator
+    /// - `frame` must no longer be in use
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`FrameAllocatorError::Unsupported`] if the allocator can't deallocate frames.
+    unsafe fn deallocate_frame(&mut self, frame: Frame<S>) -> Result<(), FrameAllocatorError>;
+}
+
+/// Counters describing how much physical memory an allocator manages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// Total number of usable frames managed by the allocator.
+    pub total_frames: usize,
+    /// Number of frames that are currently free.
+    pub free_frames: usize,
+}
+
+impl FrameStats {
+    /// Number of frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Amount of free physical memory in bytes.
+    pub fn free_bytes(&self) -> u64 {
+        self.free_frames as u64 * FrameSize4K::SIZE
+    }
+}
+
+/// The allocator currently backing [`frame_allocator()`].
+///
+/// Starts out as a [`BumpFrameAllocator`] and is replaced by a
+/// [`BuddyFrameAllocator`] when [`upgrade()`] is called.
+#[allow(clippy::large_enum_variant)] // There is only ever a single instance, stored in a static
+pub enum GlobalFrameAllocator {
+    Bump(BumpFrameAllocator),
+    Buddy(BuddyFrameAllocator),
+}
+
+impl GlobalFrameAllocator {
+    /// Returns the current frame counters, or `None` if the allocator doesn't track them.
+    pub fn stats(&self) -> Option<FrameStats> {
+        match self {
+            Self::Bump(_) => None,
+            Self::Buddy(buddy) => Some(buddy.stats()),
+        }
+    }
+
+    /// Allocates `2^order` physically contiguous, naturally aligned frames.
+    ///
+    /// See [`BuddyFrameAllocator::allocate_contiguous()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FrameAllocatorError::Unsupported`] during early boot, otherwise any error
+    /// returned by [`BuddyFrameAllocator::allocate_contiguous()`].
+    pub fn allocate_contiguous(&mut self, order: usize) -> Result<PhysAddr, FrameAllocatorError> {
+        match self {
+            Self::Bump(_) => Err(FrameAllocatorError::Unsupported),
+            Self::Buddy(buddy) => buddy.allocate_contiguous(order),
+        }
+    }
+
+    /// Splits an allocated huge `frame` into the 4 KiB frames it is made of, in ascending order.
+    ///
+    /// Each of the returned frames may be deallocated individually.
+    pub fn split_frame<S: FrameSize>(
+        &mut self,
+        frame: Frame<S>,
+    ) -> impl Iterator<Item = Frame<FrameSize4K>> + use<S> {
+        if let Self::Buddy(buddy) = self {
+            buddy.record_split(S::ORDER);
+        }
+        frame.split()
+    }
+
+    /// Deallocates a block previously returned by [`allocate_contiguous()`](Self::allocate_contiguous).
+    ///
+    /// # Safety
+    ///
+    /// See [`BuddyFrameAllocator::deallocate_contiguous()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FrameAllocatorError::Unsupported`] during early boot, when the block can't have
+    /// been allocated in the first place.
+    pub unsafe fn deallocate_contiguous(
+        &mut self,
+        addr: PhysAddr,
+        order: usize,
+    ) -> Result<(), FrameAllocatorError> {
+        match self {
+            Self::Bump(_) => Err(FrameAllocatorError::Unsupported),
+            // Safety: The caller upholds the safety contract of `deallocate_contiguous`
+            Self::Buddy(buddy) => {
+                unsafe { buddy.deallocate_contiguous(addr, order) };
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The early-boot bump allocator only hands out 4 KiB frames, so huge frames
+/// can only be allocated once the allocator has been [upgraded](upgrade).
+unsafe impl<S: FrameSize> FrameAllocator<S> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame<S>, FrameAllocatorError> {
+        match self {
+            Self::Bump(bump) if S::SIZE == FrameSize4K::SIZE => {
+                let frame: Frame<FrameSize4K> = bump.allocate_frame()?;
+                Frame::containing(frame.start_addr())
+                    .map_err(|_| FrameAllocatorError::InvalidFrameSize)
+            }
+            Self::Bump(_) => Err(FrameAllocatorError::Unsupported),
+            Self::Buddy(buddy) => buddy.allocate_frame(),
+        }
+    }
+
+    unsafe fn deallocate_frame(&mut self, frame: Frame<S>) -> Result<(), FrameAllocatorError> {
+        match self {
+            Self::Bump(_) => Err(FrameAllocatorError::Unsupported),
+            // Safety: The caller upholds the safety contract of `deallocate_frame`
+            Self::Buddy(buddy) => unsafe { buddy.deallocate_frame(frame) },
+        }
+    }
+}
+
+/// Initializes the global frame allocator.
+///
+/// Must be called **once during early kernel initialization** before any calls
+/// to [`frame_allocator()`]. This function:
+///
+/// - Initializes the memory map subsystem via [`mem_map::init()`].
+/// - Constructs a global [`BumpFrameAllocator`].
+///
+/// If the frame allocator has already been initialized, This function does nothing.
+///
+/// # Panics
+///
+/// This function panics if no memory map was recieved from the bootloader
+pub fn init() {
+    mem_map::init(
+        MEM_MAP_REQUEST
+            .get_response()
+            .expect("Should have recieved memory map from Limine"),
+    );
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(GlobalFrameAllocator::Bump(BumpFrameAllocator::new())));
+}
+
+/// Replaces the early-boot [`BumpFrameAllocator`] with a [`BuddyFrameAllocator`].
+///
+/// Every frame handed out by the bump allocator so far remains allocated.
+/// If the allocator has already been upgraded, this function does nothing.
+///
+/// # Panics
+///
+/// Panics if [`init()`] has not yet been called.
+pub fn upgrade() {
+    let mut allocator = frame_allocator();
+    if let GlobalFrameAllocator::Bump(bump) = &*allocator {
+        let buddy = BuddyFrameAllocator::new(BitmapFrameAllocator::new(bump));
+        let stats = buddy.stats();
+        log::info!(
+            "Buddy frame allocator online: {} frames free ({} MiB), {} used",
+            stats.free_frames,
+            stats.free_bytes() / (1024 * 1024),
+            stats.used_frames()
+        );
+        for order in 0..=buddy::MAX_ORDER {
+            let free_blocks = buddy.order_stats(order).free_blocks;
+            if free_blocks > 0 {
+                log::debug!("  order {order:>2}: {free_blocks} free blocks");
+            }
+        }
+        *allocator = GlobalFrameAllocator::Buddy(buddy);
+    }
+}
+
+/// Hands every [`EntryType::BOOTLOADER_RECLAIMABLE`] region to the frame allocator.
+///
+/// This must only be called once nothing refers to bootloader memory anymore: the memory map,
+/// HHDM offset and framebuffer must have been copied out of their responses, and the kernel must
+/// be running on its own page tables. The region holding the current stack is left alone,
+/// since Limine places the boot stack in reclaimable memory.
+///
+/// # Panics
+///
+/// Panics if the allocator hasn't been [upgraded](upgrade) yet.
+pub fn reclaim() {
+    let stack = paging::page_table()
+        .translate(registers::read_rsp())
+        .expect("The current stack is mapped")
+        .addr;
+
+    let mut allocator = frame_allocator();
+    let GlobalFrameAllocator::Buddy(buddy) = &mut *allocator else {
+        panic!("Bootloader memory can only be reclaimed by the buddy allocator");
+    };
+
+    let mut frames = 0;
+    for entry in mmap_iter().filter(|entry| entry.entry_type == EntryType::BOOTLOADER_RECLAIMABLE) {
+        let start = PhysAddr::new(entry.base);
+        let end = PhysAddr::new(entry.base + entry.length);
+        if (start..end).contains(&stack) {
+            log::debug!("Keeping boot stack region {start:?}..{end:?}");
+            continue;
+        }
+
+        // Safety: Nothing refers to bootloader memory anymore, and every reclaimable region
+        // is covered by the free map and mapped in the HHDM
+        frames += unsafe { buddy.add_region(start, end) };
+    }
+
+    log::info!(
+        "Reclaimed {} KiB of bootloader memory",
+        frames as u64 * FrameSize4K::SIZE / 1024
+    );
+}
+
+/// Returns a locked reference to the [`GlobalFrameAllocator`].
+///
+/// This function blocks if another thread currently holds the lock.
+///
+/// # Panics
+///
+/// Panics if [`init()`] has not yet been called.
+pub fn frame_allocator() -> MmGuard<GlobalFrameAllocator> {
+    lock::lock(
+        FRAME_ALLOCATOR
+            .get()
+            .expect("Frame allocator is initialized"),
+    )
+}
@@ -0,0 +1,112 @@
+//! # Frame Metadata
+//!
+//! Per-frame bookkeeping for every allocatable physical frame, stored in an array indexed by
+//! physical frame number.
+//!
+//! For now the only metadata is a reference count, which tracks how many mappings share a frame
+//! copy-on-write. A count of zero means the frame isn't shared, and has a single owner.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use limine::memory_map::EntryType;
+use spin::Once;
+
+use crate::memory::{
+    frame_allocator::{Frame, FrameSize, FrameSize4K},
+    mem_map::mmap_iter,
+    paging::PageTableFlags,
+    vmm,
+};
+
+static FRAME_METADATA: Once<&'static [FrameMetadata]> = Once::new();
+
+/// Metadata of a single 4 KiB frame.
+#[repr(transparent)]
+pub struct FrameMetadata {
+    ref_count: AtomicU32,
+}
+
+impl FrameMetadata {
+    /// Returns the number of mappings sharing this frame, or zero if it isn't shared.
+    pub fn ref_count(&self) -> u32 {
+        self.ref_count.load(Ordering::Acquire)
+    }
+
+    /// Returns `true` if more than one mapping refers to this frame.
+    pub fn is_shared(&self) -> bool {
+        self.ref_count() > 1
+    }
+
+    /// Records one more mapping of this frame.
+    pub fn share(&self) {
+        // An unshared frame already has one mapping, so sharing it makes two
+        self.ref_count
+            .update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                count.max(1) + 1
+            });
+    }
+
+    /// Records that one mapping of this frame went away.
+    ///
+    /// Returns `true` if it was the last one, in which case the frame may be deallocated.
+    pub fn unshare(&self) -> bool {
+        let previous = self
+            .ref_count
+            .update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                // A single remaining mapping owns the frame outright
+                if count <= 2 { 0 } else { count - 1 }
+            });
+        previous <= 1
+    }
+}
+
+/// Allocates and zeroes the metadata array, covering every usable and bootloader-reclaimable frame.
+///
+/// Must be called after the kernel's virtual address space has been initialized.
+/// If the metadata has already been initialized, this function does nothing.
+///
+/// # Panics
+///
+/// Panics if the array can't be allocated.
+pub fn init() {
+    FRAME_METADATA.call_once(|| {
+        let end = mmap_iter()
+            .filter(|entry| {
+                entry.entry_type == EntryType::USABLE
+                    || entry.entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+            })
+            .map(|entry| entry.base + entry.length)
+            .max()
+            .unwrap_or(0);
+        let frames = usize::try_from(end / FrameSize4K::SIZE).expect("Frame count fits in usize");
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_EXECUTE;
+        let region = vmm::kernel_space()
+            .allocate((frames * size_of::<FrameMetadata>()) as u64, flags, false)
+            .expect("Should be able to allocate the frame metadata array");
+
+        let metadata = region.start().as_mut_ptr::<FrameMetadata>();
+        // Safety: The region was just mapped, is large enough for `frames` entries,
+        // and a zeroed `AtomicU32` is a valid `FrameMetadata`
+        unsafe {
+            metadata.write_bytes(0, frames);
+            core::slice::from_raw_parts(metadata, frames)
+        }
+    });
+
+    log::debug!(
+        "Frame metadata covers {} frames",
+        FRAME_METADATA.get().map_or(0, |metadata| metadata.len())
+    );
+}
+
+/// Returns the metadata of `frame`, if it is covered by the metadata array.
+///
+/// Returns `None` before [`init()`] has been called.
+pub fn metadata(frame: Frame<FrameSize4K>) -> Option<&'static FrameMetadata> {
+    let index = usize::try_from(frame.start_addr().as_u64() / FrameSize4K::SIZE).ok()?;
+    FRAME_METADATA.get()?.get(index)
+}
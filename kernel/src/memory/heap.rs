@@ -0,0 +1,409 @@
+//! # Kernel Heap
+//!
+//! The kernel heap lives in a dedicated virtual address range starting at [`HEAP_START`].
+//! Only [`INITIAL_SIZE`] bytes are mapped up front; whenever an allocation doesn't fit, the heap
+//! grows by mapping more frames from [`frame_allocator()`] at its end, up to [`MAX_SIZE`].
+//!
+//! Free memory is tracked by a [`LinkedListAllocator`]: a linked list of free regions sorted by
+//! address, stored inside the free memory itself. Adjacent free regions are merged on
+//! deallocation to keep fragmentation down.
+//!
+//! Small allocations don't touch the heap at all: the [`KernelAllocator`] routes them to the
+//! size-class caches of the [slab allocator](crate::memory::slab).
+//!
+//! If the heap cannot grow any further, the out-of-memory handler logs the heap statistics and panics.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::{
+    arch::tlb,
+    memory::{
+        addr::VirtAddr,
+        frame_allocator::{FrameAllocator, FrameSize, FrameSize4K, frame_allocator},
+        paging::{self, Page, PageTableFlags},
+        slab,
+    },
+};
+
+/// Start of the heap's virtual address range.
+pub const HEAP_START: u64 = 0xffff_c000_0000_0000;
+
+/// Maximum size the heap can grow to.
+pub const MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Number of bytes mapped when the heap is initialized.
+pub const INITIAL_SIZE: u64 = 1024 * 1024;
+
+/// Minimum number of bytes mapped whenever the heap grows.
+const GROW_SIZE: u64 = 64 * 1024;
+
+/// The global kernel heap.
+///
+/// Initialized via [`init()`].
+static HEAP: Once<Mutex<Heap>> = Once::new();
+
+/// Errors that can occur while growing the heap.
+#[derive(Debug, Clone, Copy)]
+pub enum HeapError {
+    /// A frame to back the heap could not be allocated.
+    FrameAllocationFailed,
+    /// A heap page could not be mapped.
+    MappingFailed,
+    /// The heap has reached [`MAX_SIZE`].
+    HeapExhausted,
+}
+
+/// Counters describing the state of the heap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// Number of bytes currently mapped for the heap.
+    pub mapped_bytes: u64,
+    /// Number of bytes currently handed out, including padding.
+    pub used_bytes: u64,
+    /// Number of allocations made so far.
+    pub allocations: usize,
+    /// Number of deallocations made so far.
+    pub deallocations: usize,
+}
+
+/// Header stored at the start of every free region.
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+/// Smallest block the allocator hands out, since every freed block must be able to hold a [`FreeRegion`].
+const MIN_BLOCK: usize = size_of::<FreeRegion>();
+
+/// A first-fit allocator over an address-ordered linked list of free regions.
+pub struct LinkedListAllocator {
+    head: *mut FreeRegion,
+}
+
+// Safety: The free list is only ever accessed through the heap's mutex
+unsafe impl Send for LinkedListAllocator {}
+
+/// Returns the size and alignment actually used for `layout`, so that every block can hold a [`FreeRegion`].
+fn adjust_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(align_of::<FreeRegion>());
+    let size = layout
+        .size()
+        .max(MIN_BLOCK)
+        .next_multiple_of(align_of::<FreeRegion>());
+    (size, align)
+}
+
+impl LinkedListAllocator {
+    /// Creates an allocator without any free memory.
+    pub const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// Adds the memory region `[start, start + size)` to the free list, merging it with its neighbours.
+    ///
+    /// # Safety
+    ///
+    /// - The region must be mapped, writable, unused, and not already part of the free list.
+    /// - `start` must be aligned to [`FreeRegion`] and `size` must be at least [`MIN_BLOCK`].
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        debug_assert!(start.is_multiple_of(align_of::<FreeRegion>()));
+        debug_assert!(size >= MIN_BLOCK);
+
+        let mut prev: *mut FreeRegion = ptr::null_mut();
+        let mut next = self.head;
+        // Safety: Every node in the list is a valid free region
+        unsafe {
+            while !next.is_null() && (next as usize) < start {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let node = start as *mut FreeRegion;
+            node.write(FreeRegion { size, next });
+
+            if !next.is_null() && start + size == next as usize {
+                (*node).size += (*next).size;
+                (*node).next = (*next).next;
+            }
+
+            if prev.is_null() {
+                self.head = node;
+            } else if prev as usize + (*prev).size == start {
+                (*prev).size += (*node).size;
+                (*prev).next = (*node).next;
+            } else {
+                (*prev).next = node;
+            }
+        }
+    }
+
+    /// Allocates a block for `layout` from the first free region that fits it.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let (size, align) = adjust_layout(layout);
+
+        let mut prev: *mut FreeRegion = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            // Safety: Every node in the list is a valid free region
+            let (region_size, next) = unsafe { ((*current).size, (*current).next) };
+            let region_start = current as usize;
+            let region_end = region_start + region_size;
+
+            // Padding in front of the block must be large enough to stay on the free list
+            let mut alloc_start = region_start.next_multiple_of(align);
+            if alloc_start != region_start && alloc_start - region_start < MIN_BLOCK {
+                alloc_start = (region_start + MIN_BLOCK).next_multiple_of(align);
+            }
+            let alloc_end = alloc_start.saturating_add(size);
+            let excess = region_end.saturating_sub(alloc_end);
+
+            if alloc_end <= region_end && (excess == 0 || excess >= MIN_BLOCK) {
+                // Safety: `prev` is either null or a valid free region preceding `current`,
+                // and the front and back padding are free memory split off `current`
+                unsafe {
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if alloc_start > region_start {
+                        self.add_region(region_start, alloc_start - region_start);
+                    }
+                    if excess > 0 {
+                        self.add_region(alloc_end, excess);
+                    }
+                }
+
+                return NonNull::new(alloc_start as *mut u8);
+            }
+
+            prev = current;
+            current = next;
+        }
+
+        None
+    }
+
+    /// Returns a block previously handed out by [`allocate()`](Self::allocate) to the free list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`allocate()`](Self::allocate) on this allocator with
+    /// the same `layout`, and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = adjust_layout(layout);
+        // Safety: The caller guarantees the block was allocated from this allocator and is no longer in use
+        unsafe { self.add_region(ptr.as_ptr() as usize, size) };
+    }
+}
+
+/// The kernel heap: a [`LinkedListAllocator`] over a growable, mapped virtual range.
+pub struct Heap {
+    free_list: LinkedListAllocator,
+    end: u64,
+    stats: HeapStats,
+}
+
+impl Heap {
+    /// Returns the current heap statistics.
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Maps at least `bytes` more bytes at the end of the heap and adds them to the free list.
+    ///
+    /// # Errors
+    ///
+    /// - [`HeapError::HeapExhausted`] if the heap would grow beyond [`MAX_SIZE`].
+    /// - [`HeapError::FrameAllocationFailed`] if physical memory ran out.
+    /// - [`HeapError::MappingFailed`] if a heap page couldn't be mapped.
+    pub fn grow(&mut self, bytes: u64) -> Result<(), HeapError> {
+        let bytes = bytes.max(GROW_SIZE).next_multiple_of(FrameSize4K::SIZE);
+        if self.end + bytes > HEAP_START + MAX_SIZE {
+            return Err(HeapError::HeapExhausted);
+        }
+
+        let start = self.end;
+        let mut result = Ok(());
+        {
+            let mut page_table = paging::page_table();
+            let mut allocator = frame_allocator();
+            while self.end < start + bytes {
+                let Ok(frame) = allocator.allocate_frame() else {
+                    result = Err(HeapError::FrameAllocationFailed);
+                    break;
+                };
+                let page = Page::<FrameSize4K>::containing(VirtAddr::new(self.end));
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::GLOBAL
+                    | PageTableFlags::NO_EXECUTE;
+                if page_table
+                    .map_to(page, frame, flags, &mut *allocator)
+                    .is_err()
+                {
+                    // Safety: The frame was just allocated and never got mapped
+                    unsafe { allocator.deallocate_frame(frame) }
+                        .expect("Frames are freed once the buddy allocator is online");
+                    result = Err(HeapError::MappingFailed);
+                    break;
+                }
+
+                self.end += FrameSize4K::SIZE;
+            }
+        }
+
+        if self.end > start {
+            let size = usize::try_from(self.end - start).expect("Heap growth fits in usize");
+            // Safety: The range was just mapped and nothing else uses it
+            unsafe {
+                self.free_list.add_region(
+                    usize::try_from(start).expect("Addresses fit in usize"),
+                    size,
+                );
+            };
+            self.stats.mapped_bytes += self.end - start;
+            log::debug!("Heap grew to {} KiB", self.stats.mapped_bytes / 1024);
+        }
+
+        result
+    }
+
+    /// Allocates a block for `layout`, growing the heap if no free region is large enough.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let ptr = if let Some(ptr) = self.free_list.allocate(layout) {
+            ptr
+        } else {
+            // Enough for the worst case, where both the padding in front of the block, bumped to
+            // fit on the free list, and the space left after it need a free block of their own
+            let (size, align) = adjust_layout(layout);
+            self.grow((size + align + 2 * MIN_BLOCK) as u64).ok()?;
+            self.free_list.allocate(layout)?
+        };
+
+        self.stats.used_bytes += adjust_layout(layout).0 as u64;
+        self.stats.allocations += 1;
+        Some(ptr)
+    }
+
+    /// Returns a block to the heap.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`allocate()`](Self::allocate) with the same `layout`,
+    /// and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // Safety: Guaranteed by the caller
+        unsafe { self.free_list.deallocate(ptr, layout) };
+        self.stats.used_bytes -= adjust_layout(layout).0 as u64;
+        self.stats.deallocations += 1;
+    }
+}
+
+/// The kernel's [`GlobalAlloc`].
+///
+/// Allocations small enough for one of the slab size classes are served by that class,
+/// everything else comes from the global [`Heap`].
+pub struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match slab::size_class(layout) {
+            Some(cache) => tlb::lock(cache).allocate().ok(),
+            None => heap().allocate(layout),
+        };
+        ptr.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).expect("Deallocated pointers are never null");
+        // Safety: `GlobalAlloc` guarantees the block was allocated by us with the same layout,
+        // so it's routed to the same place it was allocated from
+        unsafe {
+            match slab::size_class(layout) {
+                Some(cache) => tlb::lock(cache).deallocate(ptr),
+                None => heap().deallocate(ptr, layout),
+            }
+        }
+    }
+}
+
+/// Initializes the kernel heap, mapping the first [`INITIAL_SIZE`] bytes.
+///
+/// Must be called after paging and the frame allocator have been set up.
+/// If the heap has already been initialized, this function does nothing.
+///
+/// # Panics
+///
+/// Panics if the initial heap memory can't be mapped.
+pub fn init() {
+    HEAP.call_once(|| {
+        let mut heap = Heap {
+            free_list: LinkedListAllocator::new(),
+            end: HEAP_START,
+            stats: HeapStats::default(),
+        };
+        heap.grow(INITIAL_SIZE)
+            .expect("Should be able to map the initial heap");
+        log::info!(
+            "Kernel heap at {:?}, {} KiB mapped",
+            VirtAddr::new(HEAP_START),
+            INITIAL_SIZE / 1024
+        );
+        Mutex::new(heap)
+    });
+}
+
+/// Returns a locked reference to the kernel [`Heap`].
+///
+/// This function blocks if another thread currently holds the lock.
+///
+/// # Panics
+///
+/// Panics if [`init()`] has not yet been called.
+pub fn heap() -> MutexGuard<'static, Heap> {
+    tlb::lock(HEAP.get().expect("Heap is initialized"))
+}
+
+/// Called when a heap allocation fails. Logs the heap statistics and panics.
+#[alloc_error_handler]
+fn out_of_memory(layout: Layout) -> ! {
+    let stats = heap().stats();
+    log::error!(
+        "Out of memory allocating {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+    log::error!(
+        "Heap: {} of {} KiB used, {} allocations, {} deallocations",
+        stats.used_bytes / 1024,
+        stats.mapped_bytes / 1024,
+        stats.allocations,
+        stats.deallocations
+    );
+    for (name, stats) in slab::size_class_stats().filter(|(_, stats)| stats.slabs > 0) {
+        log::error!(
+            "Slab cache {name}: {} objects in use, {} KiB in {} slabs",
+            stats.objects_in_use,
+            stats.total_bytes() / 1024,
+            stats.slabs
+        );
+    }
+    if let Some(frames) = frame_allocator().stats() {
+        log::error!(
+            "Physical memory: {} of {} frames free",
+            frames.free_frames,
+            frames.total_frames
+        );
+    }
+
+    panic!("Kernel heap exhausted");
+}
@@ -0,0 +1,67 @@
+//! # Memory manager locks
+//!
+//! Page fault resolvers need the locks of the code that faults: the kernel address space, the
+//! page table and the frame allocator. A lock held by another CPU is released soon, so a
+//! resolver can wait for it, but one held by the faulting CPU never is. Those locks are taken
+//! with [`lock()`], which counts the locks each CPU holds, so that resolvers can tell the two
+//! cases apart with [`held()`].
+//!
+//! Waiting for a lock serves TLB shootdowns, like [`tlb::lock()`], as the CPU holding it may be
+//! waiting for this one to flush its TLB.
+
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::Ordering,
+};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::{arch::tlb, percpu};
+
+/// A guard of one of the memory manager's locks, counted while it's alive.
+pub struct MmGuard<T: ?Sized + 'static> {
+    guard: MutexGuard<'static, T>,
+}
+
+impl<T: ?Sized> Deref for MmGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MmGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for MmGuard<T> {
+    fn drop(&mut self) {
+        percpu!(mm_locks).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Locks `mutex`, counting it as held by the calling CPU until the guard is dropped.
+pub fn lock<T: ?Sized>(mutex: &'static Mutex<T>) -> MmGuard<T> {
+    // Counted before spinning, as a fault can't come from the spin itself
+    percpu!(mm_locks).fetch_add(1, Ordering::Relaxed);
+    MmGuard {
+        guard: tlb::lock(mutex),
+    }
+}
+
+/// Locks `mutex` like [`lock()`], without blocking.
+///
+/// Returns `None` if another thread currently holds the lock.
+pub fn try_lock<T: ?Sized>(mutex: &'static Mutex<T>) -> Option<MmGuard<T>> {
+    let guard = mutex.try_lock()?;
+    percpu!(mm_locks).fetch_add(1, Ordering::Relaxed);
+    Some(MmGuard { guard })
+}
+
+/// Returns `true` if the calling CPU holds one of the memory manager's locks.
+pub fn held() -> bool {
+    percpu!(mm_locks).load(Ordering::Relaxed) != 0
+}
@@ -10,31 +10,25 @@
 //! - Utilities for querying and updating the memory map
 #![allow(dead_code)]
 
-use core::{mem, ptr};
-
+use limine::memory_map::{Entry, EntryType};
 use spin::Once;
 
 static MEM_MAP: Once<MemMap> = Once::new();
 
+/// Maximum number of memory map entries the kernel can keep track of.
+const MAX_ENTRIES: usize = 256;
+
 /// Represents the kernel's memory map.
 ///
-/// The `MemMap` struct holds metadata and a pointer to the memory map entries provided
-/// by the bootloader. It allows the kernel to access information about available,
-/// reserved, and special memory regions.
-#[derive(Debug)]
+/// The `MemMap` struct holds a copy of the memory map entries provided by the bootloader,
+/// so that the bootloader's memory can be reclaimed without losing access to them.
+/// It allows the kernel to access information about available, reserved, and special
+/// memory regions.
 struct MemMap {
-    size: usize,
-    entry_size: usize,
-    entries: *const limine::memory_map::Entry,
+    len: usize,
+    entries: [Entry; MAX_ENTRIES],
 }
 
-// SAFETY: The bootloader guarantees that the memory map entries pointed to by `entries`
-// are valid, read-only, and remain accessible for the entire lifetime of the kernel.
-// No thread will mutate or deallocate this memory, making concurrent and cross-thread
-// access safe. If these guarantees are ever violated, undefined behavior may occur.
-unsafe impl Send for MemMap {}
-unsafe impl Sync for MemMap {}
-
 // Returns an iterator over the memory map entries.
 ///
 /// Each item in the iterator is a copy of a `limine::memory_map::Entry` describing a region
@@ -44,26 +38,41 @@ unsafe impl Sync for MemMap {}
 /// # Panics
 ///
 /// Panics if the memory map has not been initialized via [`init`].
-pub fn mmap_iter() -> impl Iterator<Item = limine::memory_map::Entry> {
+pub fn mmap_iter() -> impl Iterator<Item = Entry> {
     let mem_map = MEM_MAP.get().expect("Memory map is initialized");
-    (0..mem_map.size).step_by(mem_map.entry_size).map(|off| {
-        // Safety: We're in bounds and each `entry_size` points to a memory map entry
-        unsafe { ptr::read_unaligned(mem_map.entries.byte_add(off)) }
-    })
+    mem_map.entries[..mem_map.len].iter().copied()
 }
 
 // Initializes the global memory map from the bootloader's memory map response.
 ///
 /// This function must be called exactly once during kernel initialization, before any
-/// calls to [`mmap_iter`]. It stores the memory map metadata and a pointer to the entries
-/// for later access. If the memory map has already been initialized this function does nothing
+/// calls to [`mmap_iter`]. It copies the memory map entries into kernel memory for later
+/// access. If the memory map has already been initialized this function does nothing
+///
+/// # Panics
+///
+/// Panics if the bootloader reports more than [`MAX_ENTRIES`] entries.
 pub fn init(mem_map: &limine::response::MemoryMapResponse) {
-    let entry_size = mem::size_of::<limine::memory_map::Entry>();
-    let size = entry_size * mem_map.entries().len();
+    MEM_MAP.call_once(|| {
+        let source = mem_map.entries();
+        assert!(
+            source.len() <= MAX_ENTRIES,
+            "Memory map has {} entries, only {MAX_ENTRIES} are supported",
+            source.len()
+        );
+
+        let mut entries = [Entry {
+            base: 0,
+            length: 0,
+            entry_type: EntryType::RESERVED,
+        }; MAX_ENTRIES];
+        for (entry, source) in entries.iter_mut().zip(source) {
+            *entry = **source;
+        }
 
-    MEM_MAP.call_once(|| MemMap {
-        size,
-        entry_size,
-        entries: ptr::from_ref(mem_map.entries()[0]),
+        MemMap {
+            len: source.len(),
+            entries,
+        }
     });
 }
@@ -6,9 +6,27 @@
 //!
 //! Submodules:
 //! - [`addr`]: Abstraction around physical and virtual addresses
+//! - [`fault`]: Resolution of page faults.
+//! - [`frame_meta`]: Per-frame metadata such as reference counts.
 //! - [`frame_allocator`] - Handles allocating and deallocating frames of physical memory.
+//! - [`heap`]: The kernel heap backing the `alloc` crate.
+//! - [`lock`]: Locks of the memory manager, tracked per CPU for the fault resolvers.
 //! - [`mem_map`]: Handles memory mapping and related operations.
+//! - [`paging`]: Manages the four-level page table hierarchy.
+//! - [`slab`]: Caches of fixed-size kernel objects.
+//! - [`stack`]: Guard-paged kernel stacks.
+//! - [`user`]: Mapping of user accessible memory in the lower half.
+//! - [`vmm`]: Allocation of kernel virtual address ranges.
 
-mod addr;
+pub mod addr;
+pub mod fault;
 pub mod frame_allocator;
+pub mod frame_meta;
+pub mod heap;
+pub mod lock;
 pub mod mem_map;
+pub mod paging;
+pub mod slab;
+pub mod stack;
+pub mod user;
+pub mod vmm;
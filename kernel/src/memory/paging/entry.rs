@@ -0,0 +1,87 @@
+//! Page table entries and their flags.
+
+use crate::memory::addr::PhysAddr;
+
+/// Mask of the physical address bits (12..52) stored in a page table entry.
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+bitflags::bitflags! {
+    /// Flags stored in a page table entry.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct PageTableFlags: u64 {
+        /// The entry is valid and the page or table it points to is present in memory.
+        const PRESENT = 1;
+        /// Writes are allowed to the memory mapped by this entry.
+        const WRITABLE = 1 << 1;
+        /// Ring 3 code may access the memory mapped by this entry.
+        const USER = 1 << 2;
+        /// Use write-through caching instead of write-back.
+        const WRITE_THROUGH = 1 << 3;
+        /// Disable caching entirely for the memory mapped by this entry.
+        const NO_CACHE = 1 << 4;
+        /// Set by the CPU when the memory mapped by this entry is accessed.
+        const ACCESSED = 1 << 5;
+        /// Set by the CPU when the page mapped by this entry is written to.
+        const DIRTY = 1 << 6;
+        /// The entry maps a 2 MiB (level 2) or 1 GiB (level 3) page instead of pointing to a table.
+        const HUGE_PAGE = 1 << 7;
+        /// The mapping is not flushed from the TLB when `CR3` is reloaded.
+        const GLOBAL = 1 << 8;
+        /// Software-defined: the page is shared copy-on-write. It is mapped read-only, and
+        /// gets copied on the first write.
+        const COPY_ON_WRITE = 1 << 9;
+        /// Instruction fetches from the memory mapped by this entry are forbidden.
+        ///
+        /// Requires `EFER.NXE` to be enabled.
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+/// A single 64-bit entry in a page table.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    /// An entry that maps nothing.
+    pub const UNUSED: Self = Self(0);
+
+    /// Returns `true` if this entry is completely zero.
+    pub fn is_unused(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Clears this entry.
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    /// Returns the flags of this entry.
+    pub fn flags(self) -> PageTableFlags {
+        PageTableFlags::from_bits_truncate(self.0)
+    }
+
+    /// Returns the physical address this entry points to.
+    pub fn addr(self) -> PhysAddr {
+        PhysAddr::new(self.0 & ADDR_MASK)
+    }
+
+    /// Points this entry at `addr` with the given flags.
+    pub fn set(&mut self, addr: PhysAddr, flags: PageTableFlags) {
+        self.0 = (addr.as_u64() & ADDR_MASK) | flags.bits();
+    }
+
+    /// Replaces the flags of this entry, keeping the address.
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.set(self.addr(), flags);
+    }
+}
+
+impl core::fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PageTableEntry")
+            .field("addr", &self.addr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -0,0 +1,200 @@
+//! # Kernel Address Space
+//!
+//! Builds the kernel's own page table hierarchy, replacing the one set up by Limine.
+//!
+//! The kernel image is mapped section by section using the symbols exported by the
+//! linker script, enforcing W^X:
+//!
+//! | Section         | Permissions      |
+//! |-----------------|------------------|
+//! | `.text`         | read + execute   |
+//! | `.rodata`       | read only        |
+//! | `.data`/`.bss`  | read + write     |
+//!
+//! The HHDM is then recreated for every memory map region the kernel may need to access,
+//! using the largest page size possible.
+
+use limine::memory_map::EntryType;
+
+use crate::{
+    EXECUTABLE_ADDRESS_REQUEST,
+    arch::{cpuid, registers},
+    memory::{
+        addr::{PhysAddr, VirtAddr},
+        frame_allocator::{
+            Frame, FrameAllocator, FrameSize, FrameSize1G, FrameSize2M, FrameSize4K,
+        },
+        mem_map::mmap_iter,
+        paging::{OffsetPageTable, Page, PageTableFlags, table::PageTable},
+    },
+};
+
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// Memory map regions that are mapped into the HHDM.
+///
+/// Reserved and bad memory is deliberately left out, so that MMIO ranges never end up
+/// mapped as cacheable memory.
+pub const HHDM_REGIONS: [EntryType; 6] = [
+    EntryType::USABLE,
+    EntryType::BOOTLOADER_RECLAIMABLE,
+    EntryType::EXECUTABLE_AND_MODULES,
+    EntryType::ACPI_RECLAIMABLE,
+    EntryType::ACPI_NVS,
+    EntryType::FRAMEBUFFER,
+];
+
+/// Builds a new page table hierarchy containing the kernel image and the HHDM.
+///
+/// # Panics
+///
+/// Panics if the executable address wasn't provided by the bootloader, or if physical
+/// memory runs out while allocating page tables.
+pub fn build<A>(allocator: &mut A) -> OffsetPageTable
+where
+    A: FrameAllocator<FrameSize4K> + ?Sized,
+{
+    let nx = if registers::enable_nx() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        log::warn!("CPU doesn't support no-execute pages, data will be executable");
+        PageTableFlags::empty()
+    };
+    registers::enable_write_protect();
+
+    let level_4 = allocator
+        .allocate_frame()
+        .expect("Should be able to allocate the level 4 page table");
+    // Safety: The frame was just allocated, so nothing else references it
+    unsafe { PageTable::from_phys(level_4.start_addr()) }.zero();
+    // Safety: The table was just created, so nothing else manages it
+    let mut page_table = unsafe { OffsetPageTable::new(level_4.start_addr()) };
+
+    let executable = EXECUTABLE_ADDRESS_REQUEST
+        .get_response()
+        .expect("Should have recieved the executable address from Limine");
+    let virt_to_phys = |virt: VirtAddr| {
+        PhysAddr::new(virt.as_u64() - executable.virtual_base() + executable.physical_base())
+    };
+
+    let base = PageTableFlags::PRESENT | PageTableFlags::GLOBAL;
+    let sections = [
+        (
+            &raw const __text_start,
+            &raw const __text_end,
+            base,
+            ".text",
+        ),
+        (
+            &raw const __rodata_start,
+            &raw const __rodata_end,
+            base | nx,
+            ".rodata",
+        ),
+        (
+            &raw const __data_start,
+            &raw const __data_end,
+            base | PageTableFlags::WRITABLE | nx,
+            ".data",
+        ),
+    ];
+
+    for (start, end, flags, name) in sections {
+        let start = VirtAddr::new(start as u64);
+        let end = VirtAddr::new(end as u64)
+            .align_up(FrameSize4K::SIZE)
+            .expect("Kernel sections end in the higher half");
+        log::debug!("Mapping {name} {start:?}..{end:?} with {flags:?}");
+
+        for virt in (start..end).step_by(4096) {
+            let page = Page::<FrameSize4K>::containing(virt);
+            let frame = Frame::from_start_addr(virt_to_phys(virt))
+                .expect("Kernel sections are page aligned");
+            page_table
+                .map_to(page, frame, flags, allocator)
+                .expect("Kernel sections don't overlap");
+        }
+    }
+
+    let hhdm_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL | nx;
+    let huge_1g = cpuid::has_1g_pages();
+    let mut regions = mmap_iter()
+        .filter(|entry| HHDM_REGIONS.contains(&entry.entry_type))
+        .map(|entry| {
+            let start = PhysAddr::new(entry.base).align_down(FrameSize4K::SIZE);
+            let end = PhysAddr::new(entry.base + entry.length).align_up(FrameSize4K::SIZE);
+            (
+                start.expect("Frame size is a power of two"),
+                end.expect("Frame size is a power of two"),
+            )
+        })
+        .peekable();
+
+    // The memory map is sorted, so adjacent regions can be merged to make room for larger pages
+    while let Some((start, mut end)) = regions.next() {
+        while let Some(&(next_start, next_end)) = regions.peek()
+            && next_start <= end
+        {
+            end = end.max(next_end);
+            regions.next();
+        }
+
+        map_hhdm_range(&mut page_table, start, end, hhdm_flags, huge_1g, allocator);
+    }
+
+    page_table
+}
+
+/// Maps the physical range `[start, end)` into the HHDM using the largest pages possible.
+fn map_hhdm_range<A>(
+    page_table: &mut OffsetPageTable,
+    start: PhysAddr,
+    end: PhysAddr,
+    flags: PageTableFlags,
+    huge_1g: bool,
+    allocator: &mut A,
+) where
+    A: FrameAllocator<FrameSize4K> + ?Sized,
+{
+    log::debug!("Mapping HHDM {start:?}..{end:?}");
+
+    let mut phys = start;
+    while phys < end {
+        let virt = phys.as_hhdm();
+        let fits = |size: u64| phys.is_aligned(size) && virt.is_aligned(size) && phys + size <= end;
+
+        let (result, size) = if huge_1g && fits(FrameSize1G::SIZE) {
+            let page = Page::<FrameSize1G>::containing(virt);
+            let frame = Frame::from_start_addr(phys).expect("Address is 1 GiB aligned");
+            (
+                page_table.map_to(page, frame, flags, allocator),
+                FrameSize1G::SIZE,
+            )
+        } else if fits(FrameSize2M::SIZE) {
+            let page = Page::<FrameSize2M>::containing(virt);
+            let frame = Frame::from_start_addr(phys).expect("Address is 2 MiB aligned");
+            (
+                page_table.map_to(page, frame, flags, allocator),
+                FrameSize2M::SIZE,
+            )
+        } else {
+            let page = Page::<FrameSize4K>::containing(virt);
+            let frame = Frame::from_start_addr(phys).expect("Address is 4 KiB aligned");
+            (
+                page_table.map_to(page, frame, flags, allocator),
+                FrameSize4K::SIZE,
+            )
+        };
+
+        result.expect("HHDM regions don't overlap");
+        phys += size;
+    }
+}
@@ -0,0 +1,379 @@
+//! Mapping, unmapping and translating pages through the HHDM.
+
+use crate::{
+    arch::tlb,
+    memory::{
+        addr::{PhysAddr, VirtAddr},
+        frame_allocator::{Frame, FrameAllocator, FrameSize, FrameSize4K},
+        frame_meta,
+        paging::{
+            Page, PagingError, entry::PageTableEntry, entry::PageTableFlags, table::PageTable,
+        },
+    },
+};
+
+/// The result of translating a virtual address.
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    /// The physical address the virtual address maps to.
+    pub addr: PhysAddr,
+    /// Size in bytes of the page containing the address.
+    pub page_size: u64,
+    /// Flags of the leaf entry mapping the page.
+    pub flags: PageTableFlags,
+}
+
+/// A four-level page table hierarchy whose tables are accessed through the HHDM.
+pub struct OffsetPageTable {
+    level_4: PhysAddr,
+}
+
+/// Returns the level of the entry that maps a page of size `S`.
+fn leaf_level<S: FrameSize>() -> usize {
+    S::ORDER / 9 + 1
+}
+
+/// Checks that `flags` only make `addr` user accessible if it's in the lower half.
+///
+/// The higher half is shared by every address space, so none of its tables may ever get
+/// [`PageTableFlags::USER`], not even intermediate ones.
+fn check_user(addr: VirtAddr, flags: PageTableFlags) -> Result<(), PagingError> {
+    if flags.contains(PageTableFlags::USER) && addr.is_higher_half() {
+        return Err(PagingError::UserPageInKernelHalf);
+    }
+    Ok(())
+}
+
+/// Size in bytes of the memory mapped by a single entry at `level`.
+fn entry_size(level: usize) -> u64 {
+    FrameSize4K::SIZE << (9 * (level - 1))
+}
+
+impl OffsetPageTable {
+    /// Creates a new page table manager for the level 4 table at `level_4`.
+    ///
+    /// # Safety
+    ///
+    /// - `level_4` must point to a valid level 4 page table.
+    /// - No other [`OffsetPageTable`] may manage the same hierarchy at the same time.
+    pub unsafe fn new(level_4: PhysAddr) -> Self {
+        Self { level_4 }
+    }
+
+    /// Returns the physical address of the level 4 table, as loaded into `CR3`.
+    pub fn level_4_addr(&self) -> PhysAddr {
+        self.level_4
+    }
+
+    fn level_4_table(&self) -> &PageTable {
+        // Safety: `level_4` is a valid page table owned by this mapper
+        unsafe { PageTable::from_phys(self.level_4) }
+    }
+
+    fn level_4_table_mut(&mut self) -> &mut PageTable {
+        // Safety: `level_4` is a valid page table owned by this mapper, which is borrowed mutably
+        unsafe { PageTable::from_phys(self.level_4) }
+    }
+
+    /// Walks down to the entry mapping `addr` at `level`, without creating missing tables.
+    fn walk(&mut self, addr: VirtAddr, level: usize) -> Result<&mut PageTableEntry, PagingError> {
+        let mut table = self.level_4_table_mut();
+
+        for current in (level + 1..=4).rev() {
+            let entry = table[addr.page_table_index(current)];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return Err(PagingError::PageNotMapped);
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(PagingError::ParentEntryHugePage);
+            }
+
+            // Safety: Present non-huge entries always point to the next level's page table
+            table = unsafe { PageTable::from_phys(entry.addr()) };
+        }
+
+        Ok(&mut table[addr.page_table_index(level)])
+    }
+
+    /// Walks down to the entry mapping `addr` at `level`, allocating any missing intermediate tables.
+    fn walk_create<A>(
+        &mut self,
+        addr: VirtAddr,
+        level: usize,
+        user: bool,
+        allocator: &mut A,
+    ) -> Result<&mut PageTableEntry, PagingError>
+    where
+        A: FrameAllocator<FrameSize4K> + ?Sized,
+    {
+        let mut parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if user {
+            parent_flags |= PageTableFlags::USER;
+        }
+
+        let mut table = self.level_4_table_mut();
+
+        for current in (level + 1..=4).rev() {
+            let entry = &mut table[addr.page_table_index(current)];
+
+            if entry.is_unused() {
+                let frame = allocator
+                    .allocate_frame()
+                    .map_err(|_| PagingError::FrameAllocationFailed)?;
+                // Safety: The frame was just allocated, so nothing else references it
+                unsafe { PageTable::from_phys(frame.start_addr()) }.zero();
+                entry.set(frame.start_addr(), parent_flags);
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(PagingError::ParentEntryHugePage);
+            } else if !entry.flags().contains(parent_flags) {
+                entry.set_flags(entry.flags() | parent_flags);
+            }
+
+            // Safety: Present non-huge entries always point to the next level's page table
+            table = unsafe { PageTable::from_phys(entry.addr()) };
+        }
+
+        Ok(&mut table[addr.page_table_index(level)])
+    }
+
+    /// Maps `page` to `frame` with the given flags.
+    ///
+    /// Missing intermediate page tables are allocated from `allocator`. Huge pages automatically
+    /// get [`PageTableFlags::HUGE_PAGE`] set.
+    ///
+    /// # Errors
+    ///
+    /// - [`PagingError::PageAlreadyMapped`] if the page is already mapped.
+    /// - [`PagingError::ParentEntryHugePage`] if the page lies inside an existing huge page.
+    /// - [`PagingError::FrameAllocationFailed`] if an intermediate table could not be allocated.
+    /// - [`PagingError::UserPageInKernelHalf`] if `flags` make a higher half page user accessible.
+    pub fn map_to<S, A>(
+        &mut self,
+        page: Page<S>,
+        frame: Frame<S>,
+        flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<(), PagingError>
+    where
+        S: FrameSize,
+        A: FrameAllocator<FrameSize4K> + ?Sized,
+    {
+        check_user(page.start_addr(), flags)?;
+        let level = leaf_level::<S>();
+        let entry = self.walk_create(
+            page.start_addr(),
+            level,
+            flags.contains(PageTableFlags::USER),
+            allocator,
+        )?;
+
+        if !entry.is_unused() {
+            return Err(PagingError::PageAlreadyMapped);
+        }
+
+        let flags = if level > 1 {
+            flags | PageTableFlags::HUGE_PAGE
+        } else {
+            flags
+        };
+        entry.set(frame.start_addr(), flags);
+
+        Ok(())
+    }
+
+    /// Unmaps `page`, returning the frame it was mapped to and flushing it from the TLB.
+    ///
+    /// The frame itself is not deallocated.
+    ///
+    /// # Errors
+    ///
+    /// - [`PagingError::PageNotMapped`] if the page is not mapped.
+    /// - [`PagingError::ParentEntryHugePage`] if the page lies inside a larger huge page.
+    /// - [`PagingError::SizeMismatch`] if the page is mapped with smaller pages.
+    pub fn unmap<S: FrameSize>(&mut self, page: Page<S>) -> Result<Frame<S>, PagingError> {
+        let entry = self.leaf_entry(page)?;
+
+        // Huge page entries store their PAT bit in bit 12, which isn't part of the address
+        let frame = Frame::from_start_addr(PhysAddr::new(entry.addr().as_u64() & !(S::SIZE - 1)))
+            .map_err(|_| PagingError::SizeMismatch)?;
+        entry.set_unused();
+        tlb::flush(page.start_addr());
+
+        Ok(frame)
+    }
+
+    /// Replaces the flags of the entry mapping `page`, flushing it from the TLB.
+    ///
+    /// # Errors
+    ///
+    /// - [`PagingError::PageNotMapped`] if the page is not mapped.
+    /// - [`PagingError::ParentEntryHugePage`] if the page lies inside a larger huge page.
+    /// - [`PagingError::SizeMismatch`] if the page is mapped with smaller pages.
+    /// - [`PagingError::UserPageInKernelHalf`] if `flags` make a higher half page user accessible.
+    pub fn update_flags<S: FrameSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        check_user(page.start_addr(), flags)?;
+        let level = leaf_level::<S>();
+        let entry = self.leaf_entry(page)?;
+
+        let flags = if level > 1 {
+            flags | PageTableFlags::HUGE_PAGE
+        } else {
+            flags
+        };
+        entry.set_flags(flags);
+        tlb::flush(page.start_addr());
+
+        Ok(())
+    }
+
+    /// Maps `target` to the frame mapped at `source`, sharing it copy-on-write.
+    ///
+    /// If `source` is writable, both mappings become read-only and get
+    /// [`PageTableFlags::COPY_ON_WRITE`] set, so the first write to either of them copies the frame.
+    /// Read-only pages are simply shared. The frame's reference count is incremented, see
+    /// [`frame_meta`](crate::memory::frame_meta).
+    ///
+    /// # Errors
+    ///
+    /// - [`PagingError::PageNotMapped`] if `source` is not mapped.
+    /// - [`PagingError::ParentEntryHugePage`] if `source` lies inside a huge page.
+    /// - [`PagingError::PageAlreadyMapped`] if `target` is already mapped.
+    /// - [`PagingError::FrameAllocationFailed`] if an intermediate table could not be allocated.
+    pub fn share_copy_on_write<A>(
+        &mut self,
+        source: Page,
+        target: Page,
+        allocator: &mut A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<FrameSize4K> + ?Sized,
+    {
+        let entry = self.leaf_entry(source)?;
+        let frame = Frame::from_start_addr(entry.addr()).map_err(|_| PagingError::SizeMismatch)?;
+        let flags = entry.flags();
+        let shared_flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | PageTableFlags::COPY_ON_WRITE
+        } else {
+            flags
+        };
+
+        self.map_to(
+            target,
+            frame,
+            shared_flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY,
+            allocator,
+        )?;
+        if shared_flags != flags {
+            self.leaf_entry(source)?.set_flags(shared_flags);
+            tlb::flush(source.start_addr());
+        }
+        frame_meta::metadata(frame)
+            .expect("Shared frames are covered by the frame metadata")
+            .share();
+
+        Ok(())
+    }
+
+    /// Makes a copy-on-write `page` writable again, after a write to it.
+    ///
+    /// If the frame is still shared, its contents are copied into a new frame from `allocator`,
+    /// which then replaces it in this mapping. Otherwise this was the last mapping, and it gets
+    /// to keep the frame.
+    ///
+    /// # Errors
+    ///
+    /// - [`PagingError::PageNotMapped`] if the page is not mapped.
+    /// - [`PagingError::ParentEntryHugePage`] if the page lies inside a huge page.
+    /// - [`PagingError::NotCopyOnWrite`] if the page isn't mapped copy-on-write.
+    /// - [`PagingError::FrameAllocationFailed`] if the copy could not be allocated.
+    pub fn resolve_copy_on_write<A>(
+        &mut self,
+        page: Page,
+        allocator: &mut A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<FrameSize4K> + ?Sized,
+    {
+        let entry = self.leaf_entry(page)?;
+        if !entry.flags().contains(PageTableFlags::COPY_ON_WRITE) {
+            return Err(PagingError::NotCopyOnWrite);
+        }
+
+        let frame: Frame<FrameSize4K> =
+            Frame::from_start_addr(entry.addr()).map_err(|_| PagingError::SizeMismatch)?;
+        let flags = (entry.flags() - PageTableFlags::COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let metadata =
+            frame_meta::metadata(frame).expect("Shared frames are covered by the frame metadata");
+
+        if metadata.is_shared() {
+            let copy: Frame<FrameSize4K> = allocator
+                .allocate_frame()
+                .map_err(|_| PagingError::FrameAllocationFailed)?;
+            // Safety: The copy was just allocated, and the shared frame is read-only everywhere
+            unsafe { copy.copy_from(frame) };
+            entry.set(copy.start_addr(), flags);
+        } else {
+            entry.set_flags(flags);
+        }
+        // Other mappings keep using the frame if it was shared, otherwise this one now owns it
+        metadata.unshare();
+        tlb::flush(page.start_addr());
+
+        Ok(())
+    }
+
+    /// Returns the present leaf entry mapping `page` with exactly the size `S`.
+    fn leaf_entry<S: FrameSize>(
+        &mut self,
+        page: Page<S>,
+    ) -> Result<&mut PageTableEntry, PagingError> {
+        let level = leaf_level::<S>();
+        let entry = self.walk(page.start_addr(), level)?;
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(PagingError::PageNotMapped);
+        }
+        if level > 1 && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(PagingError::SizeMismatch);
+        }
+
+        Ok(entry)
+    }
+
+    /// Translates a virtual address into the physical address it is mapped to,
+    /// along with the size and flags of the page mapping it.
+    ///
+    /// Returns `None` if the address is not mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        let mut table = self.level_4_table();
+
+        for level in (1..=4).rev() {
+            let entry = table[addr.page_table_index(level)];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                let page_size = entry_size(level);
+                return Some(Translation {
+                    addr: PhysAddr::new(
+                        (entry.addr().as_u64() & !(page_size - 1))
+                            | (addr.as_u64() & (page_size - 1)),
+                    ),
+                    page_size,
+                    flags,
+                });
+            }
+
+            // Safety: Present non-huge entries always point to the next level's page table
+            table = unsafe { PageTable::from_phys(entry.addr()) };
+        }
+
+        None
+    }
+}
@@ -0,0 +1,196 @@
+//! # Paging
+//!
+//! This module manages the `x86_64` four-level page table hierarchy
+//! (PML4 → PDPT → PD → PT). Page tables are accessed through the HHDM,
+//! so any table can be modified given only its physical address.
+//!
+//! Submodules:
+//! - [`entry`]: Page table entries and their flags.
+//! - [`table`]: A single page table at any level.
+//! - [`mapper`]: Mapping, unmapping and translating pages of any size.
+//! - [`kernel`]: Construction of the kernel's own address space.
+//!
+//! Pages can be shared *copy-on-write* with
+//! [`OffsetPageTable::share_copy_on_write`]: every mapping of the frame becomes read-only,
+//! and a page fault resolver gives the first writer its own copy of the frame.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use crate::memory::{
+//!     frame_allocator::{Frame, FrameSize4K, frame_allocator},
+//!     paging::{self, Page, PageTableFlags},
+//! };
+//!
+//! fn example(page: Page, frame: Frame<FrameSize4K>) {
+//!     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+//!     paging::page_table()
+//!         .map_to(page, frame, flags, &mut *frame_allocator())
+//!         .unwrap();
+//! }
+//! ```
+
+use core::marker::PhantomData;
+
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::registers,
+    memory::{
+        addr::VirtAddr,
+        fault::{self, FaultResolution, PageFault, PageFaultErrorCode},
+        frame_allocator::{FrameSize, FrameSize4K, frame_allocator},
+        lock::{self, MmGuard},
+    },
+};
+
+pub mod entry;
+pub mod kernel;
+pub mod mapper;
+pub mod table;
+
+pub use entry::PageTableFlags;
+pub use mapper::OffsetPageTable;
+
+/// The page table hierarchy currently loaded in `CR3`.
+///
+/// Initialized via [`init()`].
+static PAGE_TABLE: Once<Mutex<OffsetPageTable>> = Once::new();
+
+/// Errors that can occur while modifying page tables.
+#[derive(Debug, Clone, Copy)]
+pub enum PagingError {
+    /// A frame for an intermediate page table could not be allocated.
+    FrameAllocationFailed,
+    /// The page is already mapped.
+    PageAlreadyMapped,
+    /// The page is not mapped.
+    PageNotMapped,
+    /// A higher-level entry on the way to the page maps a huge page covering it.
+    ParentEntryHugePage,
+    /// The page is mapped, but with a different page size than requested.
+    SizeMismatch,
+    /// The page is not mapped copy-on-write.
+    NotCopyOnWrite,
+    /// A user accessible page was requested in the kernel's higher half.
+    UserPageInKernelHalf,
+}
+
+/// Represents a single page of virtual memory.
+///
+/// This is the virtual counterpart to [`Frame`](crate::memory::frame_allocator::Frame),
+/// and uses the same [`FrameSize`] markers to describe its size.
+pub struct Page<S: FrameSize = FrameSize4K> {
+    start_addr: VirtAddr,
+    size: PhantomData<S>,
+}
+
+impl<S: FrameSize> Page<S> {
+    /// Returns the page containing the given virtual address.
+    pub fn containing(addr: VirtAddr) -> Self {
+        Self {
+            start_addr: addr
+                .align_down(S::SIZE)
+                .expect("Page sizes are powers of two"),
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the starting virtual address of this page.
+    pub fn start_addr(self) -> VirtAddr {
+        self.start_addr
+    }
+}
+
+impl<S: FrameSize> Clone for Page<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: FrameSize> Copy for Page<S> {}
+
+impl<S: FrameSize> core::fmt::Debug for Page<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "Page[{}]({:?})",
+            S::SIZE_STR,
+            self.start_addr()
+        ))
+    }
+}
+
+/// Builds the kernel's own page tables and switches `CR3` over to them.
+///
+/// The page tables set up by Limine are abandoned. See [`kernel`] for the layout of the
+/// new address space.
+///
+/// If paging has already been initialized, this function does nothing.
+pub fn init() {
+    PAGE_TABLE.call_once(|| {
+        log::debug!(
+            "Bootloader level 4 page table at {:?}",
+            registers::read_cr3()
+        );
+
+        let page_table = kernel::build(&mut *frame_allocator());
+        let level_4 = page_table.level_4_addr();
+        // Safety: The new hierarchy maps the kernel image and the HHDM, which contains the stack,
+        // the framebuffer and all memory handed to us by the bootloader
+        unsafe { registers::write_cr3(level_4) };
+        log::debug!("Switched to kernel level 4 page table at {level_4:?}");
+
+        Mutex::new(page_table)
+    });
+
+    fault::register_resolver("copy-on-write", copy_on_write_resolver);
+}
+
+/// Gives the faulting mapping its own copy of a copy-on-write page on the first write to it.
+fn copy_on_write_resolver(fault: &PageFault) -> FaultResolution {
+    if !fault
+        .error_code
+        .contains(PageFaultErrorCode::PRESENT | PageFaultErrorCode::WRITE)
+    {
+        return FaultResolution::NotHandled;
+    }
+
+    // Code holding one of the locks faulted, waiting for them would deadlock
+    if lock::held() {
+        return FaultResolution::NotHandled;
+    }
+    let mut page_table = page_table();
+    let mut allocator = frame_allocator();
+
+    let page = Page::containing(fault.addr);
+    match page_table.resolve_copy_on_write(page, &mut *allocator) {
+        Ok(()) => FaultResolution::Resolved,
+        Err(PagingError::NotCopyOnWrite) => FaultResolution::NotHandled,
+        Err(PagingError::FrameAllocationFailed) => {
+            FaultResolution::Fatal("Out of physical memory while copying a page")
+        }
+        Err(err) => {
+            log::error!("Copy-on-write of {:?} failed: {err:?}", fault.addr);
+            FaultResolution::Fatal("Failed to copy a copy-on-write page")
+        }
+    }
+}
+
+/// Returns a locked reference to the active [`OffsetPageTable`].
+///
+/// This function blocks if another thread currently holds the lock.
+///
+/// # Panics
+///
+/// Panics if [`init()`] has not yet been called.
+pub fn page_table() -> MmGuard<OffsetPageTable> {
+    lock::lock(PAGE_TABLE.get().expect("Paging is initialized"))
+}
+
+/// Returns a locked reference to the active [`OffsetPageTable`], without blocking.
+///
+/// Returns `None` if paging hasn't been initialized or another thread currently holds the lock.
+/// This is meant for code that may run while the lock is held, such as exception handlers.
+pub fn try_page_table() -> Option<MmGuard<OffsetPageTable>> {
+    lock::try_lock(PAGE_TABLE.get()?)
+}
@@ -0,0 +1,46 @@
+//! Page tables.
+
+use core::ops::{Index, IndexMut};
+
+use crate::memory::{addr::PhysAddr, paging::entry::PageTableEntry};
+
+/// Number of entries in a page table at every level.
+pub const ENTRY_COUNT: usize = 512;
+
+/// A single page table at any level of the hierarchy (PML4, PDPT, PD or PT).
+#[derive(Clone)]
+#[repr(C, align(4096))]
+pub struct PageTable {
+    entries: [PageTableEntry; ENTRY_COUNT],
+}
+
+impl PageTable {
+    /// Clears every entry in this table.
+    pub fn zero(&mut self) {
+        self.entries.fill(PageTableEntry::UNUSED);
+    }
+
+    /// Returns a reference to the page table stored in the frame at `addr`, accessed through the HHDM.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be the physical address of a valid page table, and the caller must ensure
+    /// no other reference to the same table is alive for the lifetime `'a`.
+    pub unsafe fn from_phys<'a>(addr: PhysAddr) -> &'a mut Self {
+        unsafe { &mut *addr.as_hhdm().as_mut_ptr::<Self>() }
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.entries[index]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.entries[index]
+    }
+}
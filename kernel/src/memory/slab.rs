@@ -0,0 +1,413 @@
+//! # Slab Allocator
+//!
+//! Object caches for allocating lots of same-sized objects quickly and without fragmentation.
+//!
+//! Every cache carves *slabs* (physically contiguous, naturally aligned blocks of frames from the
+//! [`frame_allocator()`]) into equally sized objects, accessed through the HHDM. Each slab starts
+//! with a small header linking it into its cache and tracking its free objects:
+//!
+//! - Slabs with at least one free object are kept on the cache's *partial* list.
+//! - Slabs with every object in use are moved to the *full* list.
+//! - Slabs that become completely empty are returned to the frame allocator, unless
+//!   they're the last partial slab of their cache.
+//!
+//! Since slabs are aligned to their own size, the slab owning an object is found by
+//! masking the object's address.
+//!
+//! There are two ways to use slabs:
+//! - [`SlabCache<T>`]: a named, typed cache, e.g. `SlabCache::<Task>::new("task")`.
+//! - The kernel's global allocator routes small allocations to a set of size-class
+//!   caches (see [`size_class()`]).
+
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    ptr::{self, NonNull},
+};
+
+use spin::Mutex;
+
+use crate::{
+    arch::tlb,
+    memory::{
+        addr::VirtAddr,
+        frame_allocator::{FrameAllocatorError, FrameSize, FrameSize4K, frame_allocator},
+    },
+};
+
+/// Minimum number of objects that should fit in a single slab.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Size in bytes of the smallest slab.
+#[allow(clippy::cast_possible_truncation)] // 4096 fits in any usize
+const MIN_SLAB_SIZE: usize = FrameSize4K::SIZE as usize;
+
+/// Header stored at the start of every slab.
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Free list node stored inside every free object.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Statistics of a single slab cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    /// Size in bytes of each object, including padding.
+    pub object_size: usize,
+    /// Size in bytes of each slab.
+    pub slab_size: usize,
+    /// Number of objects that fit in a single slab.
+    pub objects_per_slab: usize,
+    /// Number of slabs currently owned by the cache.
+    pub slabs: usize,
+    /// Number of objects currently allocated.
+    pub objects_in_use: usize,
+    /// Number of allocations made so far.
+    pub allocations: usize,
+    /// Number of deallocations made so far.
+    pub deallocations: usize,
+}
+
+impl SlabStats {
+    /// Total number of bytes of physical memory held by the cache.
+    pub fn total_bytes(&self) -> usize {
+        self.slabs * self.slab_size
+    }
+}
+
+/// An untyped cache of objects with a fixed size and alignment.
+pub struct RawSlabCache {
+    name: &'static str,
+    object_size: usize,
+    first_object: usize,
+    slab_order: usize,
+    partial: *mut SlabHeader,
+    full: *mut SlabHeader,
+    stats: SlabStats,
+}
+
+// Safety: The slabs are owned by the cache and only ever accessed through it
+unsafe impl Send for RawSlabCache {}
+
+impl RawSlabCache {
+    /// Creates an empty cache for objects of `size` bytes aligned to `align`.
+    ///
+    /// No memory is allocated until the first object is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+
+        let align = if align > align_of::<FreeObject>() {
+            align
+        } else {
+            align_of::<FreeObject>()
+        };
+        let size = if size > size_of::<FreeObject>() {
+            size
+        } else {
+            size_of::<FreeObject>()
+        };
+        let object_size = size.next_multiple_of(align);
+        let first_object = size_of::<SlabHeader>().next_multiple_of(align);
+
+        let mut slab_order = 0;
+        while (MIN_SLAB_SIZE << slab_order) - first_object < MIN_OBJECTS_PER_SLAB * object_size {
+            slab_order += 1;
+        }
+        let slab_size = MIN_SLAB_SIZE << slab_order;
+
+        Self {
+            name,
+            object_size,
+            first_object,
+            slab_order,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            stats: SlabStats {
+                object_size,
+                slab_size,
+                objects_per_slab: (slab_size - first_object) / object_size,
+                slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                deallocations: 0,
+            },
+        }
+    }
+
+    /// Returns the name of this cache.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the statistics of this cache.
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    /// Allocates an uninitialized object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a new slab was needed and couldn't be allocated.
+    pub fn allocate(&mut self) -> Result<NonNull<u8>, FrameAllocatorError> {
+        if self.partial.is_null() {
+            self.grow()?;
+        }
+
+        let slab = self.partial;
+        // Safety: Slabs on the partial list are valid and have at least one free object
+        let object = unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+
+            if (*slab).free.is_null() {
+                Self::unlink(&mut self.partial, slab);
+                Self::push(&mut self.full, slab);
+            }
+
+            object
+        };
+
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+
+        Ok(NonNull::new(object.cast()).expect("Slab objects are never null"))
+    }
+
+    /// Returns an object to its slab.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been allocated from this cache and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, object: NonNull<u8>) {
+        let object = object.cast::<FreeObject>().as_ptr();
+        let slab = (object as usize & !(self.stats.slab_size - 1)) as *mut SlabHeader;
+
+        // Safety: The caller guarantees the object belongs to this cache, so `slab` is one of our slabs
+        unsafe {
+            let was_full = (*slab).free.is_null();
+            object.write(FreeObject { next: (*slab).free });
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+
+            if was_full {
+                Self::unlink(&mut self.full, slab);
+                Self::push(&mut self.partial, slab);
+            }
+
+            // Keep the last partial slab around to avoid thrashing the frame allocator
+            if (*slab).in_use == 0 && !(self.partial == slab && (*slab).next.is_null()) {
+                Self::unlink(&mut self.partial, slab);
+                self.release(slab);
+            }
+        }
+
+        self.stats.objects_in_use -= 1;
+        self.stats.deallocations += 1;
+    }
+
+    /// Allocates a new slab and puts it on the partial list.
+    fn grow(&mut self) -> Result<(), FrameAllocatorError> {
+        let addr = frame_allocator().allocate_contiguous(self.slab_order)?;
+        let slab = addr.as_hhdm().as_mut_ptr::<SlabHeader>();
+
+        // Thread every object onto the free list, lowest address first
+        let mut free = ptr::null_mut();
+        for index in (0..self.stats.objects_per_slab).rev() {
+            let object =
+                (slab as usize + self.first_object + index * self.object_size) as *mut FreeObject;
+            // Safety: The object lies within the slab that was just allocated
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        // Safety: The slab was just allocated, is mapped in the HHDM, and is large enough to hold its header
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+            Self::push(&mut self.partial, slab);
+        }
+        self.stats.slabs += 1;
+
+        Ok(())
+    }
+
+    /// Returns an unlinked, empty slab to the frame allocator.
+    unsafe fn release(&mut self, slab: *mut SlabHeader) {
+        let addr = VirtAddr::new(slab as u64)
+            .to_phys_if_hhdm()
+            .expect("Slabs are accessed through the HHDM");
+        // Safety: The slab was allocated with this order and none of its objects are in use
+        unsafe { frame_allocator().deallocate_contiguous(addr, self.slab_order) }
+            .expect("Slabs are only allocated once the buddy allocator is online");
+        self.stats.slabs -= 1;
+    }
+
+    /// Pushes `slab` onto the front of `list`.
+    unsafe fn push(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+        // Safety: The caller guarantees `slab` is a valid, unlinked slab and `list` only holds valid slabs
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = *list;
+            if !(*list).is_null() {
+                (**list).prev = slab;
+            }
+        }
+        *list = slab;
+    }
+
+    /// Removes `slab` from `list`.
+    unsafe fn unlink(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+        // Safety: The caller guarantees `slab` is a valid slab currently on `list`
+        unsafe {
+            if (*slab).prev.is_null() {
+                *list = (*slab).next;
+            } else {
+                (*(*slab).prev).next = (*slab).next;
+            }
+            if !(*slab).next.is_null() {
+                (*(*slab).next).prev = (*slab).prev;
+            }
+        }
+    }
+}
+
+impl Drop for RawSlabCache {
+    /// Returns every empty slab to the frame allocator.
+    ///
+    /// Slabs that still hold live objects are leaked.
+    fn drop(&mut self) {
+        if self.stats.objects_in_use > 0 {
+            log::warn!(
+                "Slab cache {} dropped with {} objects in use",
+                self.name,
+                self.stats.objects_in_use
+            );
+        }
+
+        let mut slab = self.partial;
+        while !slab.is_null() {
+            // Safety: Every slab on the partial list is valid
+            unsafe {
+                let next = (*slab).next;
+                if (*slab).in_use == 0 {
+                    Self::unlink(&mut self.partial, slab);
+                    self.release(slab);
+                }
+                slab = next;
+            }
+        }
+    }
+}
+
+/// A named cache of objects of type `T`.
+///
+/// ## Example
+///
+/// ```rust
+/// static TASKS: SlabCache<Task> = SlabCache::new("task");
+///
+/// let task = TASKS.allocate(Task::new()).unwrap();
+/// // ...
+/// unsafe { TASKS.deallocate(task) };
+/// ```
+pub struct SlabCache<T> {
+    cache: Mutex<RawSlabCache>,
+    marker: PhantomData<T>,
+}
+
+impl<T> SlabCache<T> {
+    /// Creates an empty cache named `name`.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            cache: Mutex::new(RawSlabCache::new(name, size_of::<T>(), align_of::<T>())),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the name of this cache.
+    pub fn name(&self) -> &'static str {
+        tlb::lock(&self.cache).name()
+    }
+
+    /// Returns the statistics of this cache.
+    pub fn stats(&self) -> SlabStats {
+        tlb::lock(&self.cache).stats()
+    }
+
+    /// Moves `value` into a newly allocated object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a new slab was needed and couldn't be allocated.
+    pub fn allocate(&self, value: T) -> Result<NonNull<T>, FrameAllocatorError> {
+        let object = tlb::lock(&self.cache).allocate()?.cast::<T>();
+        // Safety: The object is properly sized and aligned for `T`, and unused
+        unsafe { object.write(value) };
+        Ok(object)
+    }
+
+    /// Drops the object at `object` and returns it to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been allocated from this cache and must not be used afterwards.
+    pub unsafe fn deallocate(&self, object: NonNull<T>) {
+        // Safety: Guaranteed by the caller
+        unsafe {
+            object.drop_in_place();
+            tlb::lock(&self.cache).deallocate(object.cast());
+        }
+    }
+}
+
+/// Object sizes of the general-purpose size-class caches.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// General-purpose caches used by the global allocator for small allocations.
+static SIZE_CLASS_CACHES: [Mutex<RawSlabCache>; SIZE_CLASSES.len()] = [
+    Mutex::new(RawSlabCache::new("size-8", 8, 8)),
+    Mutex::new(RawSlabCache::new("size-16", 16, 16)),
+    Mutex::new(RawSlabCache::new("size-32", 32, 32)),
+    Mutex::new(RawSlabCache::new("size-64", 64, 64)),
+    Mutex::new(RawSlabCache::new("size-128", 128, 128)),
+    Mutex::new(RawSlabCache::new("size-256", 256, 256)),
+    Mutex::new(RawSlabCache::new("size-512", 512, 512)),
+    Mutex::new(RawSlabCache::new("size-1024", 1024, 1024)),
+    Mutex::new(RawSlabCache::new("size-2048", 2048, 2048)),
+];
+
+/// Returns the size-class cache that should serve allocations with `layout`, if any.
+///
+/// Objects in size-class caches are aligned to their size, so a layout is served by the
+/// smallest class at least as large as both its size and its alignment.
+pub fn size_class(layout: Layout) -> Option<&'static Mutex<RawSlabCache>> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES
+        .iter()
+        .position(|&class| size <= class)
+        .map(|index| &SIZE_CLASS_CACHES[index])
+}
+
+/// Returns an iterator over the name and statistics of every size-class cache.
+pub fn size_class_stats() -> impl Iterator<Item = (&'static str, SlabStats)> {
+    SIZE_CLASS_CACHES.iter().map(|cache| {
+        let cache = tlb::lock(cache);
+        (cache.name(), cache.stats())
+    })
+}
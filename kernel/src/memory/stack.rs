@@ -0,0 +1,83 @@
+//! # Kernel Stacks
+//!
+//! Kernel stacks are allocated from the kernel's virtual address space, with an unmapped guard
+//! page right below them. Since stacks grow downwards, overflowing one hits the guard page
+//! instead of silently corrupting whatever lies below it.
+//!
+//! The CPU can't push an exception frame onto an overflowed stack, so the resulting page fault
+//! escalates to a double fault, which is handled on its own IST stack. Both the page fault and
+//! the double fault handler use [`overflowed_stack()`] to report which stack overflowed.
+
+use alloc::collections::BTreeMap;
+
+use spin::Mutex;
+
+use crate::memory::{
+    addr::VirtAddr,
+    frame_allocator::{FrameSize, FrameSize4K},
+    paging::PageTableFlags,
+    vmm::{self, GUARD_SIZE, VirtRegion, VmmError},
+};
+
+/// Names of all live kernel stacks, keyed by the address of their lowest byte.
+static STACKS: Mutex<BTreeMap<u64, &'static str>> = Mutex::new(BTreeMap::new());
+
+/// A kernel stack, with a guard page below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    region: VirtRegion,
+}
+
+impl KernelStack {
+    /// Allocates a stack of `pages` pages for the task called `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`VmmError`] if the stack couldn't be allocated.
+    pub fn new(name: &'static str, pages: u64) -> Result<Self, VmmError> {
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_EXECUTE;
+        let region = vmm::kernel_space().allocate(pages * FrameSize4K::SIZE, flags, true)?;
+        STACKS.lock().insert(region.start().as_u64(), name);
+
+        log::debug!(
+            "Allocated {} KiB stack for {name} at {:?}..{:?}",
+            region.size() / 1024,
+            region.start(),
+            region.end()
+        );
+        Ok(Self { region })
+    }
+
+    /// Returns the address just past the top of the stack, which is the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// Frees the stack.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may be running on the stack or reference anything on it anymore.
+    #[allow(dead_code)] // TODO: Free the stacks of exited tasks once there are tasks
+    pub unsafe fn free(self) {
+        STACKS.lock().remove(&self.region.start().as_u64());
+        // Safety: The caller guarantees the stack is unused
+        unsafe { vmm::kernel_space().free(self.region) }.expect("Stacks are allocated regions");
+    }
+}
+
+/// Returns the name of the task whose stack overflowed, if `addr` lies in the guard page of a
+/// kernel stack.
+///
+/// This never blocks, so that it can be used from exception handlers. If the stack list is
+/// locked, `None` is returned.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    let addr = addr.as_u64();
+    let stacks = STACKS.try_lock()?;
+    let (&bottom, &name) = stacks.range(addr.checked_add(1)?..).next()?;
+
+    (addr >= bottom - GUARD_SIZE).then_some(name)
+}
@@ -0,0 +1,48 @@
+//! # User Memory
+//!
+//! Ring 3 code can only access the lower half of the address space, below [`USER_END`]. User
+//! pages are mapped there through [`map()`], separately from the kernel's own regions in
+//! [`vmm`], so that no page table of the higher half ever becomes user accessible.
+//!
+//! There is only a single address space for now: user pages live in the lower half of the
+//! kernel's page tables, and callers choose their addresses themselves.
+
+use crate::memory::{
+    addr::VirtAddr,
+    frame_allocator::{FrameSize, FrameSize4K},
+    paging::{Page, PageTableFlags},
+    vmm::{self, VmmError},
+};
+
+/// End (exclusive) of the memory user code has access to: the lower half, except for its last
+/// page. A `SYSCALL` at the very end of that page would return to a non-canonical address, which
+/// `SYSRET` faults on in ring 0, still on the user stack.
+pub const USER_END: u64 = 0x0000_7fff_ffff_f000;
+
+/// Maps `size` bytes starting at `start` to freshly allocated frames, user accessible with `flags`.
+///
+/// # Errors
+///
+/// - [`VmmError::InvalidSize`] if `size` is zero.
+/// - [`VmmError::InvalidAddress`] if `start` isn't page aligned, or the range doesn't end below [`USER_END`].
+/// - [`VmmError::FrameAllocationFailed`] if physical memory ran out.
+/// - [`VmmError::MappingFailed`] if a page couldn't be mapped, for example because it already is.
+pub fn map(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    if size == 0 {
+        return Err(VmmError::InvalidSize);
+    }
+
+    let size = size.next_multiple_of(FrameSize4K::SIZE);
+    if !start.is_aligned(FrameSize4K::SIZE)
+        || start
+            .as_u64()
+            .checked_add(size)
+            .is_none_or(|end| end > USER_END)
+    {
+        return Err(VmmError::InvalidAddress);
+    }
+
+    let pages =
+        (0..size / FrameSize4K::SIZE).map(move |i| Page::containing(start + i * FrameSize4K::SIZE));
+    vmm::map_fresh_frames(pages, flags | PageTableFlags::USER)
+}
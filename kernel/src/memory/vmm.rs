@@ -0,0 +1,529 @@
+//! # Kernel Virtual Address Space
+//!
+//! Manages the part of the higher half between [`VMM_START`] and [`VMM_END`], which
+//! is used for everything that isn't part of the HHDM, the kernel image or the heap:
+//! kernel stacks and other dynamically mapped memory.
+//!
+//! Free ranges are tracked in a [`BTreeMap`] keyed by their start address, and adjacent
+//! free ranges are merged whenever a region is freed. Regions are always page aligned,
+//! and can optionally be surrounded by unmapped *guard pages*, so that running off either
+//! end of the region faults instead of silently corrupting a neighbour. A page fault resolver
+//! reports such faults as fatal, naming the region whose guard page was hit.
+//!
+//! Regions are either backed eagerly, with every page mapped when the region is allocated, or
+//! *on demand*: nothing is mapped up front, and the first access to each page faults. The
+//! demand paging resolver then maps a freshly zeroed frame, so that large buffers only use as
+//! much physical memory as they actually touch.
+//!
+//! Device memory is mapped with [`KernelAddressSpace::map_mmio`], uncached. Its frames don't
+//! belong to the frame allocator, so they're left alone when the region is freed.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use crate::memory::{paging::PageTableFlags, vmm};
+//!
+//! let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+//! let region = vmm::kernel_space().allocate(16 * 1024, flags, true).unwrap();
+//! // ...
+//! unsafe { vmm::kernel_space().free(region).unwrap() };
+//! ```
+
+use alloc::collections::BTreeMap;
+
+use spin::{Mutex, Once};
+
+use crate::memory::{
+    addr::{PhysAddr, VirtAddr},
+    fault::{self, FaultResolution, PageFault, PageFaultErrorCode},
+    frame_allocator::{Frame, FrameAllocator, FrameSize, FrameSize4K, frame_allocator},
+    frame_meta::{self, FrameMetadata},
+    lock::{self, MmGuard},
+    paging::{self, Page, PageTableFlags, PagingError},
+};
+
+/// Start of the managed virtual address range.
+pub const VMM_START: u64 = 0xffff_a000_0000_0000;
+
+/// End (exclusive) of the managed virtual address range.
+pub const VMM_END: u64 = 0xffff_c000_0000_0000;
+
+/// Size of a guard page.
+pub const GUARD_SIZE: u64 = FrameSize4K::SIZE;
+
+/// The kernel's virtual address space.
+///
+/// Initialized via [`init()`].
+static KERNEL_SPACE: Once<Mutex<KernelAddressSpace>> = Once::new();
+
+/// Errors that can occur while managing virtual memory regions.
+#[derive(Debug, Clone, Copy)]
+pub enum VmmError {
+    /// The requested size is zero.
+    InvalidSize,
+    /// No free range is large enough for the region.
+    OutOfVirtualSpace,
+    /// A frame to back the region could not be allocated.
+    FrameAllocationFailed,
+    /// A page of the region could not be mapped.
+    MappingFailed,
+    /// The region wasn't allocated by this address space.
+    UnknownRegion,
+    /// The range isn't page aligned, or lies outside of the address range it must be in.
+    InvalidAddress,
+}
+
+/// A page-aligned region of kernel virtual memory.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtRegion {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VirtRegion {
+    /// Returns the first address of the region.
+    pub fn start(self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the first address past the end of the region.
+    pub fn end(self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns the size of the region in bytes, excluding guard pages.
+    pub fn size(self) -> u64 {
+        self.size
+    }
+
+    /// Returns an iterator over every page of the region.
+    pub fn pages(self) -> impl Iterator<Item = Page<FrameSize4K>> + Clone {
+        (0..self.size / FrameSize4K::SIZE)
+            .map(move |i| Page::containing(self.start + i * FrameSize4K::SIZE))
+    }
+}
+
+/// Bookkeeping for an allocated region.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    size: u64,
+    guard: bool,
+    backing: Backing,
+}
+
+/// How the pages of a region get mapped.
+#[derive(Debug, Clone, Copy)]
+enum Backing {
+    /// Every page is mapped when the region is allocated.
+    Eager,
+    /// Pages are mapped with the given flags on first access.
+    OnDemand(PageTableFlags),
+    /// Every page is mapped to device memory when the region is allocated.
+    Mmio,
+}
+
+/// Tracks the free and allocated ranges of the kernel's virtual address space.
+pub struct KernelAddressSpace {
+    /// Free ranges, mapping start address to size.
+    free: BTreeMap<u64, u64>,
+    /// Allocated regions, keyed by the start of their usable part.
+    regions: BTreeMap<u64, Region>,
+}
+
+impl KernelAddressSpace {
+    /// Creates an address space managing `[start, end)`, with everything free.
+    pub fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(start.as_u64(), end.as_u64() - start.as_u64());
+
+        Self {
+            free,
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// Reserves a region of at least `size` bytes without mapping anything.
+    fn reserve(
+        &mut self,
+        size: u64,
+        guard: bool,
+        backing: Backing,
+    ) -> Result<VirtRegion, VmmError> {
+        if size == 0 {
+            return Err(VmmError::InvalidSize);
+        }
+
+        let size = size.next_multiple_of(FrameSize4K::SIZE);
+        let total = if guard { size + 2 * GUARD_SIZE } else { size };
+
+        let (&start, &free_size) = self
+            .free
+            .iter()
+            .find(|&(_, &free_size)| free_size >= total)
+            .ok_or(VmmError::OutOfVirtualSpace)?;
+
+        self.free.remove(&start);
+        if free_size > total {
+            self.free.insert(start + total, free_size - total);
+        }
+
+        let start = if guard { start + GUARD_SIZE } else { start };
+        self.regions.insert(
+            start,
+            Region {
+                size,
+                guard,
+                backing,
+            },
+        );
+
+        Ok(VirtRegion {
+            start: VirtAddr::new(start),
+            size,
+        })
+    }
+
+    /// Allocates a region of at least `size` bytes and maps it to freshly allocated frames.
+    ///
+    /// If `guard` is set, an unmapped guard page is kept on each side of the region.
+    ///
+    /// # Errors
+    ///
+    /// - [`VmmError::InvalidSize`] if `size` is zero.
+    /// - [`VmmError::OutOfVirtualSpace`] if no free range is large enough.
+    /// - [`VmmError::FrameAllocationFailed`] if physical memory ran out.
+    /// - [`VmmError::MappingFailed`] if a page couldn't be mapped.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        guard: bool,
+    ) -> Result<VirtRegion, VmmError> {
+        let region = self.reserve(size, guard, Backing::Eager)?;
+
+        let result = map_fresh_frames(region.pages(), flags);
+        if result.is_err() {
+            self.release(region.start.as_u64());
+        }
+
+        result.map(|()| region)
+    }
+
+    /// Allocates a region of at least `size` bytes whose pages are only backed once they're touched.
+    ///
+    /// Each page is mapped with `flags` to a zeroed frame on its first access.
+    /// If `guard` is set, an unmapped guard page is kept on each side of the region.
+    ///
+    /// # Errors
+    ///
+    /// - [`VmmError::InvalidSize`] if `size` is zero.
+    /// - [`VmmError::OutOfVirtualSpace`] if no free range is large enough.
+    pub fn allocate_on_demand(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        guard: bool,
+    ) -> Result<VirtRegion, VmmError> {
+        self.reserve(size, guard, Backing::OnDemand(flags))
+    }
+
+    /// Maps `size` bytes of device memory starting at `phys` into a new region, uncached.
+    ///
+    /// The region starts at the page containing `phys`, so the device memory is found at
+    /// `phys`'s offset within that page.
+    ///
+    /// # Errors
+    ///
+    /// - [`VmmError::InvalidSize`] if `size` is zero.
+    /// - [`VmmError::OutOfVirtualSpace`] if no free range is large enough.
+    /// - [`VmmError::FrameAllocationFailed`] if a page table couldn't be allocated.
+    /// - [`VmmError::MappingFailed`] if a page couldn't be mapped.
+    pub fn map_mmio(&mut self, phys: PhysAddr, size: u64) -> Result<VirtRegion, VmmError> {
+        let start = phys
+            .align_down(FrameSize4K::SIZE)
+            .expect("Page size is a power of two");
+        let region = self.reserve(size + (phys - start), false, Backing::Mmio)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_EXECUTE;
+
+        let mut result = Ok(region);
+        {
+            let mut page_table = paging::page_table();
+            let mut allocator = frame_allocator();
+            for (i, page) in region.pages().enumerate() {
+                let frame = Frame::from_start_addr(start + i as u64 * FrameSize4K::SIZE)
+                    .expect("Address is page aligned");
+                if let Err(err) = page_table.map_to(page, frame, flags, &mut *allocator) {
+                    for page in region.pages().take(i) {
+                        page_table.unmap(page).expect("Page was just mapped");
+                    }
+                    result = Err(match err {
+                        PagingError::FrameAllocationFailed => VmmError::FrameAllocationFailed,
+                        _ => VmmError::MappingFailed,
+                    });
+                    break;
+                }
+            }
+        }
+
+        if result.is_err() {
+            self.release(region.start.as_u64());
+        }
+
+        result
+    }
+
+    /// Unmaps and frees a region, returning its virtual range and backing frames.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VmmError::UnknownRegion`] if `region` wasn't returned by this address space.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may access the region anymore once it is freed.
+    pub unsafe fn free(&mut self, region: VirtRegion) -> Result<(), VmmError> {
+        let start = region.start.as_u64();
+        let Some(&Region { backing, .. }) = self.regions.get(&start) else {
+            return Err(VmmError::UnknownRegion);
+        };
+
+        {
+            let mut page_table = paging::page_table();
+            let mut allocator = frame_allocator();
+            for page in region.pages() {
+                // Pages of on-demand regions that were never touched aren't mapped
+                let Ok(frame) = page_table.unmap(page) else {
+                    continue;
+                };
+                if matches!(backing, Backing::Mmio) {
+                    continue;
+                }
+                // Frames shared copy-on-write stay alive until their last mapping goes away
+                if frame_meta::metadata(frame).is_none_or(FrameMetadata::unshare) {
+                    // Safety: The frame was allocated for this region, and the caller guarantees it's unused
+                    unsafe { allocator.deallocate_frame(frame) }
+                        .expect("Frames are freed once the buddy allocator is online");
+                }
+            }
+        }
+
+        self.release(start);
+        Ok(())
+    }
+
+    /// Returns the virtual range of the region starting at `start` (including guard pages) to the free pool.
+    fn release(&mut self, start: u64) {
+        let region = self
+            .regions
+            .remove(&start)
+            .expect("Released regions are allocated");
+
+        let (mut start, mut size) = if region.guard {
+            (start - GUARD_SIZE, region.size + 2 * GUARD_SIZE)
+        } else {
+            (start, region.size)
+        };
+
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+        if let Some((&prev_start, &prev_size)) = self.free.range(..start).next_back()
+            && prev_start + prev_size == start
+        {
+            start = prev_start;
+            size += prev_size;
+        }
+        self.free.insert(start, size);
+    }
+
+    /// Returns the region containing `addr`, along with whether `addr` lies in one of its guard pages.
+    pub fn region_containing(&self, addr: VirtAddr) -> Option<(VirtRegion, bool)> {
+        self.lookup(addr)
+            .map(|(region, _, in_guard)| (region, in_guard))
+    }
+
+    /// Returns the region containing `addr`, its bookkeeping, and whether `addr` lies in a guard page.
+    fn lookup(&self, addr: VirtAddr) -> Option<(VirtRegion, Region, bool)> {
+        let addr = addr.as_u64();
+        let guard_size = |region: &Region| if region.guard { GUARD_SIZE } else { 0 };
+
+        // The region starting at or below `addr`, or failing that the one whose leading guard page holds it
+        let (&start, &region) = self
+            .regions
+            .range(..=addr)
+            .next_back()
+            .filter(|&(&start, region)| addr < start + region.size + guard_size(region))
+            .or_else(|| {
+                self.regions
+                    .range(addr..)
+                    .next()
+                    .filter(|&(&start, region)| addr >= start - guard_size(region))
+            })?;
+
+        let in_guard = addr < start || addr >= start + region.size;
+        Some((
+            VirtRegion {
+                start: VirtAddr::new(start),
+                size: region.size,
+            },
+            region,
+            in_guard,
+        ))
+    }
+
+    /// Returns the number of bytes currently free.
+    pub fn free_bytes(&self) -> u64 {
+        self.free.values().sum()
+    }
+}
+
+/// Maps every page of `pages` to a freshly allocated frame.
+///
+/// If a page can't be mapped, the pages mapped so far are unmapped and their frames freed again.
+///
+/// # Errors
+///
+/// - [`VmmError::FrameAllocationFailed`] if physical memory ran out.
+/// - [`VmmError::MappingFailed`] if a page couldn't be mapped.
+pub(super) fn map_fresh_frames<I>(pages: I, flags: PageTableFlags) -> Result<(), VmmError>
+where
+    I: Iterator<Item = Page<FrameSize4K>> + Clone,
+{
+    let mut page_table = paging::page_table();
+    let mut allocator = frame_allocator();
+    for (mapped, page) in pages.clone().enumerate() {
+        let mapping = allocator
+            .allocate_frame()
+            .map_err(|_| VmmError::FrameAllocationFailed)
+            .and_then(|frame| {
+                page_table
+                    .map_to(page, frame, flags, &mut *allocator)
+                    .map_err(|_| {
+                        // Safety: The frame was just allocated and never got mapped
+                        unsafe { allocator.deallocate_frame(frame) }
+                            .expect("Frames are freed once the buddy allocator is online");
+                        VmmError::MappingFailed
+                    })
+            });
+
+        if let Err(err) = mapping {
+            // Roll back the pages mapped so far
+            for page in pages.take(mapped) {
+                let frame = page_table.unmap(page).expect("Page was just mapped");
+                // Safety: The frame was only mapped here, and the mapping is being discarded
+                unsafe { allocator.deallocate_frame(frame) }
+                    .expect("Frames are freed once the buddy allocator is online");
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Initializes the kernel's virtual address space.
+///
+/// Must be called after the heap has been initialized.
+/// If the address space has already been initialized, this function does nothing.
+pub fn init() {
+    KERNEL_SPACE.call_once(|| {
+        log::debug!(
+            "Kernel virtual address space: {:?}..{:?}",
+            VirtAddr::new(VMM_START),
+            VirtAddr::new(VMM_END)
+        );
+        Mutex::new(KernelAddressSpace::new(
+            VirtAddr::new(VMM_START),
+            VirtAddr::new(VMM_END),
+        ))
+    });
+    fault::register_resolver("guard page", guard_page_resolver);
+    fault::register_resolver("demand paging", demand_paging_resolver);
+}
+
+/// Page fault resolver reporting accesses to guard pages.
+fn guard_page_resolver(fault: &PageFault) -> FaultResolution {
+    // Code holding one of the locks faulted, waiting for them would deadlock
+    if lock::held() {
+        return FaultResolution::NotHandled;
+    }
+    let Some(space) = KERNEL_SPACE.get().map(lock::lock) else {
+        return FaultResolution::NotHandled;
+    };
+
+    match space.region_containing(fault.addr) {
+        Some((region, true)) => {
+            log::error!(
+                "{:?} is a guard page of the {} KiB region at {:?}",
+                fault.addr,
+                region.size() / 1024,
+                region.start()
+            );
+            FaultResolution::Fatal("Guard page hit, likely a stack overflow")
+        }
+        _ => FaultResolution::NotHandled,
+    }
+}
+
+/// Page fault resolver backing pages of on-demand regions with zeroed frames.
+fn demand_paging_resolver(fault: &PageFault) -> FaultResolution {
+    if fault.error_code.contains(PageFaultErrorCode::PRESENT) {
+        return FaultResolution::NotHandled;
+    }
+
+    // Code holding one of the locks faulted, waiting for them would deadlock
+    if lock::held() {
+        return FaultResolution::NotHandled;
+    }
+    let Some(space) = KERNEL_SPACE.get().map(lock::lock) else {
+        return FaultResolution::NotHandled;
+    };
+    let Some((_, region, false)) = space.lookup(fault.addr) else {
+        return FaultResolution::NotHandled;
+    };
+    let Backing::OnDemand(flags) = region.backing else {
+        return FaultResolution::NotHandled;
+    };
+
+    // The address space stays locked until the page is mapped, so the region can't be freed
+    let mut page_table = paging::page_table();
+    let mut allocator = frame_allocator();
+    let Ok(frame) = allocator.allocate_frame() else {
+        return FaultResolution::Fatal("Out of physical memory while demand paging");
+    };
+    // Safety: The frame was just allocated, so nothing else uses it
+    unsafe { frame.zero() };
+
+    let page = Page::<FrameSize4K>::containing(fault.addr);
+    match page_table.map_to(page, frame, flags, &mut *allocator) {
+        Ok(()) => FaultResolution::Resolved,
+        // Another CPU faulted on the same page and mapped it first
+        Err(PagingError::PageAlreadyMapped) => {
+            // Safety: The frame was never mapped
+            unsafe { allocator.deallocate_frame(frame) }
+                .expect("Frames are freed once the buddy allocator is online");
+            FaultResolution::Resolved
+        }
+        Err(_) => FaultResolution::Fatal("Failed to map a demand paged page"),
+    }
+}
+
+/// Returns a locked reference to the kernel's [`KernelAddressSpace`].
+///
+/// This function blocks if another thread currently holds the lock.
+///
+/// # Panics
+///
+/// Panics if [`init()`] has not yet been called.
+pub fn kernel_space() -> MmGuard<KernelAddressSpace> {
+    lock::lock(
+        KERNEL_SPACE
+            .get()
+            .expect("Kernel address space is initialized"),
+    )
+}
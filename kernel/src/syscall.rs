@@ -0,0 +1,180 @@
+//! # System Calls
+//!
+//! Architecture-independent system call dispatch.
+//!
+//! The architecture's entry code decodes the system call number and up to six raw arguments
+//! from the user's registers, and hands them to [`dispatch()`]. Each system call decodes its
+//! arguments into typed values with [`SyscallArgs::get()`], and returns a [`SyscallResult`].
+//! The result is encoded into a single register: a successful value is returned as is, an error
+//! as the negated [`SyscallError`] code.
+//!
+//! | Number | Name   | Arguments                  |
+//! |--------|--------|----------------------------|
+//! | 0      | `log`  | `message: *const u8, len`  |
+//! | 1      | `exit` | `code: i32`                |
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch,
+    memory::{
+        addr::VirtAddr,
+        paging::{self, PageTableFlags},
+    },
+};
+
+/// Longest message accepted by the `log` system call.
+const MAX_LOG_LEN: usize = 4096;
+
+/// Errors returned to user code by system calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// There is no system call with the requested number.
+    InvalidSyscall = 1,
+    /// An argument is out of range or malformed.
+    InvalidArgument = 2,
+    /// A pointer argument points to memory the caller can't access.
+    BadAddress = 3,
+}
+
+/// The result of a system call.
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// A system call implementation.
+type SyscallHandler = fn(&SyscallArgs) -> SyscallResult;
+
+/// All system calls, indexed by their number.
+const SYSCALL_TABLE: [(&str, SyscallHandler); 2] = [("log", sys_log), ("exit", sys_exit)];
+
+/// A value that can be decoded from a raw system call argument.
+pub trait SyscallArg: Sized {
+    /// Decodes the argument from its register value.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`SyscallError`] if the value isn't valid for this type.
+    fn decode(raw: u64) -> Result<Self, SyscallError>;
+}
+
+impl SyscallArg for u64 {
+    fn decode(raw: u64) -> Result<Self, SyscallError> {
+        Ok(raw)
+    }
+}
+
+impl SyscallArg for usize {
+    fn decode(raw: u64) -> Result<Self, SyscallError> {
+        Self::try_from(raw).map_err(|_| SyscallError::InvalidArgument)
+    }
+}
+
+impl SyscallArg for i32 {
+    fn decode(raw: u64) -> Result<Self, SyscallError> {
+        // 32-bit arguments only occupy the lower half of the register
+        #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+        Ok(raw as u32 as i32)
+    }
+}
+
+impl SyscallArg for VirtAddr {
+    fn decode(raw: u64) -> Result<Self, SyscallError> {
+        Self::try_new(raw).map_err(|_| SyscallError::BadAddress)
+    }
+}
+
+/// The raw arguments of a system call.
+pub struct SyscallArgs([u64; 6]);
+
+impl SyscallArgs {
+    /// Decodes argument `index` as a `T`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`SyscallError`] if the argument isn't a valid `T`.
+    pub fn get<T: SyscallArg>(&self, index: usize) -> Result<T, SyscallError> {
+        T::decode(self.0[index])
+    }
+}
+
+/// Runs system call `number` with the given raw arguments, returning the encoded result.
+pub fn dispatch(number: u64, args: [u64; 6]) -> u64 {
+    let result = usize::try_from(number)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number))
+        .map_or(Err(SyscallError::InvalidSyscall), |&(name, handler)| {
+            log::trace!("System call {name}({args:x?})");
+            handler(&SyscallArgs(args))
+        });
+
+    match result {
+        Ok(value) => value,
+        #[allow(clippy::cast_sign_loss)] // Errors are returned as negative values
+        Err(error) => (-(error as i64)) as u64,
+    }
+}
+
+/// Copies `len` bytes at `addr` out of user memory.
+///
+/// # Errors
+///
+/// Returns [`SyscallError::BadAddress`] if any of the bytes isn't mapped user accessible.
+fn copy_from_user(addr: VirtAddr, len: usize) -> Result<Vec<u8>, SyscallError> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let end = addr
+        .as_u64()
+        .checked_add(len as u64)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(SyscallError::BadAddress)?;
+
+    {
+        let page_table = paging::page_table();
+        let mut page = addr.align_down(4096).expect("Page size is a power of two");
+        while page < end {
+            let accessible = page_table.translate(page).is_some_and(|translation| {
+                translation
+                    .flags
+                    .contains(PageTableFlags::PRESENT | PageTableFlags::USER)
+            });
+            if !accessible {
+                return Err(SyscallError::BadAddress);
+            }
+            page = VirtAddr::new_truncate(page.as_u64() + 4096);
+        }
+    }
+
+    let mut buffer = Vec::with_capacity(len);
+    // Safety: Every byte of the range was just checked to be mapped user accessible
+    unsafe {
+        addr.as_ptr::<u8>()
+            .copy_to_nonoverlapping(buffer.as_mut_ptr(), len);
+        buffer.set_len(len);
+    }
+    Ok(buffer)
+}
+
+/// `log(message, len)`: writes a UTF-8 message to the kernel log.
+fn sys_log(args: &SyscallArgs) -> SyscallResult {
+    let message: VirtAddr = args.get(0)?;
+    let len: usize = args.get(1)?;
+    if len > MAX_LOG_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let bytes = copy_from_user(message, len)?;
+    let message = core::str::from_utf8(&bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    log::info!("[user] {message}");
+    Ok(len as u64)
+}
+
+/// `exit(code)`: terminates the caller.
+fn sys_exit(args: &SyscallArgs) -> SyscallResult {
+    let code: i32 = args.get(0)?;
+    log::info!("User program exited with code {code}");
+
+    // TODO: Terminate only the calling task once there are tasks
+    arch::enable_interrupts();
+    arch::halt()
+}
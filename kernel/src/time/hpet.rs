@@ -0,0 +1,353 @@
+//! The High Precision Event Timer.
+//!
+//! The HPET has a main counter incrementing at a fixed frequency of at least 10 MHz, described
+//! by the ACPI HPET table. The main counter is used as a clock source, and its *comparators*
+//! raise an interrupt when the main counter reaches their value, once or periodically.
+//!
+//! Comparator interrupts are routed through the I/O APICs, to one of the GSIs the comparator
+//! supports. They are active high and level triggered, so comparators can share a GSI: each
+//! handler checks the interrupt status register for its own comparator.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::time::Duration;
+
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::{
+        acpi::{self, Polarity, TriggerMode},
+        apic,
+        interrupts::dispatch::{Handler, HandlerId},
+        ioapic,
+    },
+    memory::{addr::VirtAddr, vmm},
+    time::{ClockSource, nanos_to_ticks},
+};
+
+/// Size of the register block.
+const MMIO_SIZE: u64 = 0x400;
+
+/// General capabilities and ID register.
+const CAPABILITIES: u64 = 0x00;
+/// General configuration register.
+const CONFIGURATION: u64 = 0x10;
+/// General interrupt status register.
+const INTERRUPT_STATUS: u64 = 0x20;
+/// Main counter value register.
+const MAIN_COUNTER: u64 = 0xf0;
+
+/// Returns the offset of comparator `index`'s configuration and capabilities register.
+const fn comparator_configuration(index: u8) -> u64 {
+    0x100 + 0x20 * index as u64
+}
+
+/// Returns the offset of comparator `index`'s value register.
+const fn comparator_value(index: u8) -> u64 {
+    0x108 + 0x20 * index as u64
+}
+
+/// Bit of [`CAPABILITIES`] set when the main counter is 64 bits wide.
+const CAPABILITIES_64_BIT: u64 = 1 << 13;
+/// Bit of [`CONFIGURATION`] starting the main counter.
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+/// Bit of [`CONFIGURATION`] routing comparators 0 and 1 to the legacy PIT and RTC IRQs.
+const CONFIGURATION_LEGACY_ROUTE: u64 = 1 << 1;
+
+/// Comparator configuration bit selecting level triggered interrupts.
+const COMPARATOR_LEVEL: u64 = 1 << 1;
+/// Comparator configuration bit enabling its interrupt.
+const COMPARATOR_ENABLE: u64 = 1 << 2;
+/// Comparator configuration bit selecting periodic mode.
+const COMPARATOR_PERIODIC: u64 = 1 << 3;
+/// Comparator capability bit set when it supports periodic mode.
+const COMPARATOR_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Comparator capability bit set when it is 64 bits wide.
+const COMPARATOR_64_BIT: u64 = 1 << 5;
+/// Comparator configuration bit letting the next value write set the periodic accumulator.
+const COMPARATOR_SET_VALUE: u64 = 1 << 6;
+/// Shift of the GSI a comparator's interrupt is routed to, in its configuration.
+const COMPARATOR_ROUTE_SHIFT: u64 = 9;
+
+/// GSIs below this are ISA IRQs, which are edge triggered.
+const FIRST_NON_ISA_GSI: u32 = 16;
+
+/// Femtoseconds per second, the unit of the counter period.
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+static HPET: Once<Hpet> = Once::new();
+
+/// Modes of an HPET comparator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparatorMode {
+    /// Fires once, after the given delay.
+    OneShot,
+    /// Fires repeatedly, with the given period.
+    Periodic,
+}
+
+/// Errors that can occur while starting an HPET timer.
+#[derive(Debug, Clone, Copy)]
+pub enum HpetError {
+    /// There is no usable HPET.
+    Unavailable,
+    /// Every comparator is in use, or doesn't support the requested mode.
+    NoFreeComparator,
+    /// No GSI the comparators support is handled by an I/O APIC.
+    NoRoute,
+    /// The delay is shorter than a tick of the main counter.
+    DurationTooShort,
+}
+
+/// A comparator, as enumerated at initialization.
+struct Comparator {
+    periodic: bool,
+    /// Bitmap of the GSIs the comparator can be routed to.
+    routes: u32,
+}
+
+pub struct Hpet {
+    base: VirtAddr,
+    frequency: u64,
+    /// Usable comparators, indexed by comparator number.
+    comparators: Vec<Option<Comparator>>,
+    /// Bitmap of the comparators in use.
+    used: Mutex<u32>,
+}
+
+/// A running HPET comparator, calling its handler whenever it fires.
+#[derive(Debug)]
+pub struct HpetTimer {
+    comparator: u8,
+    gsi: u32,
+    handler: HandlerId,
+}
+
+impl Hpet {
+    /// Maps the HPET described by ACPI and starts its main counter, with every comparator
+    /// disabled.
+    ///
+    /// Returns `None` if there is no HPET, or its main counter is only 32 bits wide.
+    fn new() -> Option<Self> {
+        let phys = acpi::hpet()
+            .inspect_err(|err| log::debug!("No usable HPET table: {err:?}"))
+            .ok()?;
+        let region = vmm::kernel_space()
+            .map_mmio(phys, MMIO_SIZE)
+            .inspect_err(|err| log::warn!("Couldn't map the HPET: {err:?}"))
+            .ok()?;
+
+        let mut hpet = Self {
+            base: region.start(),
+            frequency: 0,
+            comparators: Vec::new(),
+            used: Mutex::new(0),
+        };
+        let capabilities = hpet.read_register(CAPABILITIES);
+        // A 32-bit counter wraps every few minutes, which would need tracking
+        if capabilities & CAPABILITIES_64_BIT == 0 {
+            log::debug!("HPET main counter is 32 bits wide, not using it");
+            return None;
+        }
+
+        let period = capabilities >> 32;
+        if period == 0 {
+            return None;
+        }
+        hpet.frequency = FEMTOSECONDS_PER_SECOND / period;
+
+        // Comparators with 32-bit values would wrap long before the main counter does
+        #[allow(clippy::cast_possible_truncation)] // The field is 5 bits wide
+        let last = ((capabilities >> 8) & 0x1f) as u8;
+        hpet.comparators = (0..=last)
+            .map(|index| {
+                let register = comparator_configuration(index);
+                let configuration = hpet.read_register(register);
+                hpet.write_register(
+                    register,
+                    configuration & !(COMPARATOR_ENABLE | COMPARATOR_PERIODIC),
+                );
+                #[allow(clippy::cast_possible_truncation)] // The routes are the upper half
+                let routes = (configuration >> 32) as u32;
+                (configuration & COMPARATOR_64_BIT != 0).then_some(Comparator {
+                    periodic: configuration & COMPARATOR_PERIODIC_CAPABLE != 0,
+                    routes,
+                })
+            })
+            .collect();
+
+        let configuration = hpet.read_register(CONFIGURATION) & !CONFIGURATION_LEGACY_ROUTE;
+        hpet.write_register(CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+        log::info!(
+            "HPET at {} kHz with {} comparators",
+            hpet.frequency / 1000,
+            hpet.comparators.iter().flatten().count()
+        );
+        Some(hpet)
+    }
+
+    fn read_register(&self, offset: u64) -> u64 {
+        // Safety: The registers are mapped uncached, and 8-byte aligned
+        unsafe { (self.base + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write_register(&self, offset: u64, value: u64) {
+        // Safety: Same as `read_register()`
+        unsafe {
+            (self.base + offset)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value);
+        }
+    }
+
+    /// Claims a free comparator supporting `mode`.
+    fn claim(&self, mode: ComparatorMode) -> Option<u8> {
+        let mut used = self.used.lock();
+        let index = self
+            .comparators
+            .iter()
+            .enumerate()
+            .position(|(index, comparator)| {
+                comparator.as_ref().is_some_and(|comparator| {
+                    *used & (1 << index) == 0
+                        && (mode == ComparatorMode::OneShot || comparator.periodic)
+                })
+            })?;
+        *used |= 1 << index;
+        u8::try_from(index).ok()
+    }
+
+    fn release(&self, index: u8) {
+        *self.used.lock() &= !(1 << index);
+    }
+
+    /// Routes comparator `index`'s interrupt to `handler` on the calling CPU.
+    ///
+    /// Returns the GSI it was routed to, and the handler's ID.
+    fn route(&'static self, index: u8, handler: Handler) -> Result<(u32, HandlerId), HpetError> {
+        let comparator = self.comparators[usize::from(index)]
+            .as_ref()
+            .expect("Claimed comparators are usable");
+        if !ioapic::is_available() {
+            return Err(HpetError::NoRoute);
+        }
+
+        // The handler only runs if this comparator fired, as others may share the GSI
+        let mask = 1 << index;
+        let handler: Handler = Box::new(move |stack| {
+            if self.read_register(INTERRUPT_STATUS) & mask != 0 {
+                self.write_register(INTERRUPT_STATUS, mask);
+                handler(stack);
+            }
+        });
+
+        // Level triggered GSIs can be shared, unlike the edge triggered ISA ones
+        let gsi = (FIRST_NON_ISA_GSI..u32::BITS)
+            .find(|gsi| comparator.routes & (1 << gsi) != 0)
+            .ok_or(HpetError::NoRoute)?;
+        // Comparators drive their line high, unlike the active low PCI interrupts
+        let id = ioapic::register_irq(
+            gsi,
+            handler,
+            apic::id(),
+            Polarity::ActiveHigh,
+            TriggerMode::Level,
+        )
+        .inspect_err(|err| log::warn!("Couldn't route HPET comparator {index}: {err:?}"))
+        .map_err(|_| HpetError::NoRoute)?;
+        Ok((gsi, id))
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "HPET"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        self.read_register(MAIN_COUNTER)
+    }
+}
+
+/// Initializes the HPET, if there is one.
+pub(super) fn init() -> Option<&'static Hpet> {
+    HPET.try_call_once(|| Hpet::new().ok_or(())).ok()
+}
+
+/// Starts an HPET comparator in `mode`, calling `handler` after `duration`, and then every
+/// `duration` in periodic mode.
+///
+/// The interrupt is delivered to the calling CPU, and `handler` runs in interrupt context.
+///
+/// # Errors
+///
+/// - [`HpetError::Unavailable`] if there is no HPET.
+/// - [`HpetError::DurationTooShort`] if `duration` is less than a tick of the main counter.
+/// - [`HpetError::NoFreeComparator`] if no free comparator supports `mode`.
+/// - [`HpetError::NoRoute`] if the comparator's interrupt can't be routed.
+pub fn start_timer(
+    mode: ComparatorMode,
+    duration: Duration,
+    handler: Handler,
+) -> Result<HpetTimer, HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::Unavailable)?;
+    let ticks = nanos_to_ticks(duration, hpet.frequency);
+    if ticks == 0 {
+        return Err(HpetError::DurationTooShort);
+    }
+
+    let index = hpet.claim(mode).ok_or(HpetError::NoFreeComparator)?;
+    let (gsi, handler) = hpet
+        .route(index, handler)
+        .inspect_err(|_| hpet.release(index))?;
+
+    let mut configuration = COMPARATOR_LEVEL | (u64::from(gsi) << COMPARATOR_ROUTE_SHIFT);
+    if mode == ComparatorMode::Periodic {
+        configuration |= COMPARATOR_PERIODIC;
+    }
+
+    // The comparator stays disabled until its value is set, as a stale one could fire at once
+    let register = comparator_value(index);
+    let configuration_register = comparator_configuration(index);
+    hpet.write_register(INTERRUPT_STATUS, 1 << index);
+    if mode == ComparatorMode::Periodic {
+        hpet.write_register(configuration_register, configuration | COMPARATOR_SET_VALUE);
+    }
+    hpet.write_register(register, hpet.read() + ticks);
+    // With the value set bit, the first write sets the comparator and this one the period
+    if mode == ComparatorMode::Periodic {
+        hpet.write_register(register, ticks);
+    }
+    hpet.write_register(configuration_register, configuration | COMPARATOR_ENABLE);
+
+    log::debug!("HPET comparator {index} started in {mode:?} mode on GSI {gsi}");
+    Ok(HpetTimer {
+        comparator: index,
+        gsi,
+        handler,
+    })
+}
+
+impl HpetTimer {
+    /// Stops the comparator and unregisters its handler, masking its GSI if no other comparator
+    /// uses it.
+    pub fn stop(self) {
+        let hpet = HPET.get().expect("Timers are only started with an HPET");
+        let register = comparator_configuration(self.comparator);
+        hpet.write_register(
+            register,
+            hpet.read_register(register) & !(COMPARATOR_ENABLE | COMPARATOR_PERIODIC),
+        );
+        hpet.write_register(INTERRUPT_STATUS, 1 << self.comparator);
+
+        ioapic::unregister_irq(self.gsi, self.handler).expect("The handler is registered");
+        hpet.release(self.comparator);
+    }
+}
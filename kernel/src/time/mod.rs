@@ -0,0 +1,160 @@
+//! # Time
+//!
+//! The kernel keeps time with a single monotonic *clock source*, a free-running counter with a
+//! known frequency. Every available source is probed by [`init()`], and the best rated one is
+//! used from then on: the invariant TSC, then the HPET, then a TSC that isn't invariant.
+//!
+//! The PIT is never used as the clock source, as it only notices its counter wrapping around
+//! when read: left alone for more than 54 ms, it loses time. It only serves as a reference to
+//! calibrate the TSC against when there is no HPET.
+//!
+//! [`Instant`]s measure time since [`init()`] on that source. [`sleep()`] busy-waits, and also
+//! works before [`init()`], by counting down the PIT.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    hint::spin_loop,
+    ops::{Add, Sub},
+};
+
+use spin::Once;
+
+pub use core::time::Duration;
+
+pub mod hpet;
+mod pit;
+mod tsc;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A free-running counter, used to tell time.
+pub trait ClockSource: Sync {
+    /// Returns the name of the source, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Returns how suitable the source is. The highest rated source available is used.
+    fn rating(&self) -> u32;
+
+    /// Returns the frequency of the counter, in Hz.
+    fn frequency(&self) -> u64;
+
+    /// Returns the current value of the counter, which never decreases.
+    fn read(&self) -> u64;
+}
+
+/// The clock source in use, and its counter value when it was chosen.
+struct Clock {
+    source: &'static dyn ClockSource,
+    start: u64,
+}
+
+static CLOCK: Once<Clock> = Once::new();
+
+/// Converts `ticks` of a counter running at `frequency` Hz to a duration.
+#[allow(clippy::cast_possible_truncation)] // u64 nanoseconds cover centuries
+fn ticks_to_duration(ticks: u64, frequency: u64) -> Duration {
+    Duration::from_nanos((u128::from(ticks) * NANOS_PER_SECOND / u128::from(frequency)) as u64)
+}
+
+/// Converts `duration` to ticks of a counter running at `frequency` Hz.
+#[allow(clippy::cast_possible_truncation)] // u64 ticks cover centuries at any real frequency
+fn nanos_to_ticks(duration: Duration, frequency: u64) -> u64 {
+    (duration.as_nanos() * u128::from(frequency) / NANOS_PER_SECOND) as u64
+}
+
+/// A point in time, measured since the clock source was chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(Duration);
+
+impl Instant {
+    /// Returns the current time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the time subsystem isn't initialized.
+    pub fn now() -> Self {
+        let clock = CLOCK.get().expect("Time is initialized");
+        let ticks = clock.source.read() - clock.start;
+        Self(ticks_to_duration(ticks, clock.source.frequency()))
+    }
+
+    /// Returns the time elapsed since `earlier`, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Self) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Returns the time elapsed since this instant.
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self(self.0 + rhs)
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+/// Probes every clock source, and chooses the best rated one.
+///
+/// Must be called after the kernel's virtual address space has been initialized.
+pub fn init() {
+    let mut sources: Vec<&'static dyn ClockSource> = Vec::new();
+    if let Some(hpet) = hpet::init() {
+        sources.push(hpet);
+    }
+
+    // The TSC is calibrated against the HPET, or the PIT if there is none
+    let tsc = match sources.first() {
+        Some(hpet) => tsc::Tsc::new(*hpet),
+        None => tsc::Tsc::new(&pit::Pit::new()),
+    };
+    sources.push(Box::leak(Box::new(tsc)));
+
+    for source in &sources {
+        log::debug!(
+            "Clock source {} at {} kHz (rating {})",
+            source.name(),
+            source.frequency() / 1000,
+            source.rating()
+        );
+    }
+
+    let source = *sources
+        .iter()
+        .max_by_key(|source| source.rating())
+        .expect("The TSC is always available");
+    CLOCK.call_once(|| Clock {
+        source,
+        start: source.read(),
+    });
+    log::info!("Using {} as clock source", source.name());
+}
+
+/// Busy-waits until `deadline`.
+pub fn sleep_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        spin_loop();
+    }
+}
+
+/// Busy-waits for `duration`.
+///
+/// Before [`init()`], the PIT times the wait instead of the clock source.
+pub fn sleep(duration: Duration) {
+    if CLOCK.get().is_some() {
+        sleep_until(Instant::now() + duration);
+    } else {
+        pit::wait(duration);
+    }
+}
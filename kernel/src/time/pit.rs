@@ -0,0 +1,136 @@
+//! The legacy Programmable Interval Timer.
+//!
+//! The PIT runs at a fixed, known frequency and needs no setup, which makes it usable from early
+//! boot and a reference to calibrate other timers against. Channel 2 is used for busy-waits,
+//! since its output can be polled through port `0x61` without involving interrupts.
+//!
+//! As a clock source, channel 0 counts down repeatedly through its 16-bit counter. Wraparounds
+//! are only noticed when the counter is read, so it must be read at least every 54 ms. It is
+//! only used while calibrating other timers, which reads it continuously.
+
+use core::{hint::spin_loop, time::Duration};
+
+use spin::Mutex;
+
+use crate::{
+    arch::{
+        interrupts::without_interrupts,
+        io::{inb, outb},
+    },
+    time::ClockSource,
+};
+
+/// Frequency of the PIT's input clock, in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Data port of channel 0.
+const CHANNEL_0: u16 = 0x40;
+/// Data port of channel 2.
+const CHANNEL_2: u16 = 0x42;
+/// Mode/command register.
+const COMMAND: u16 = 0x43;
+/// Port controlling the channel 2 gate and the PC speaker.
+const GATE: u16 = 0x61;
+
+/// Channel 0, low then high byte, mode 2 (rate generator), binary.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Latches the current count of channel 0.
+const CHANNEL_0_LATCH: u8 = 0b0000_0000;
+/// Channel 2, low then high byte, mode 0 (interrupt on terminal count), binary.
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+/// Bit of [`GATE`] enabling channel 2.
+const GATE_ENABLE: u8 = 1 << 0;
+/// Bit of [`GATE`] connecting channel 2 to the PC speaker.
+const SPEAKER_ENABLE: u8 = 1 << 1;
+/// Bit of [`GATE`] reflecting the output of channel 2.
+const OUTPUT: u8 = 1 << 5;
+
+/// Longest wait a single channel 2 count can time.
+const MAX_WAIT: Duration = Duration::from_millis(50);
+
+/// Counts PIT ticks through channel 0.
+pub struct Pit {
+    /// Last count read, and the total number of ticks counted until then.
+    state: Mutex<(u16, u64)>,
+}
+
+impl Pit {
+    /// Starts channel 0 counting down repeatedly from 65536.
+    pub fn new() -> Self {
+        // Safety: Channel 0 belongs to the PIT, and its IRQ is masked
+        unsafe {
+            outb(COMMAND, CHANNEL_0_RATE_GENERATOR);
+            outb(CHANNEL_0, 0);
+            outb(CHANNEL_0, 0);
+        }
+        Self {
+            state: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "PIT"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn frequency(&self) -> u64 {
+        FREQUENCY
+    }
+
+    fn read(&self) -> u64 {
+        // Interrupts are disabled so that a handler reading the clock can't deadlock with us
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            // Safety: Latching and reading channel 0 has no other side effect
+            let count = unsafe {
+                outb(COMMAND, CHANNEL_0_LATCH);
+                u16::from_le_bytes([inb(CHANNEL_0), inb(CHANNEL_0)])
+            };
+
+            // The counter counts down, so the ticks elapsed are the distance from the last count
+            let (last, ticks) = &mut *state;
+            *ticks += u64::from(last.wrapping_sub(count));
+            *last = count;
+            *ticks
+        })
+    }
+}
+
+/// Busy-waits for `ticks` PIT ticks, at most 65535.
+fn wait_ticks(ticks: u16) {
+    let [low, high] = ticks.to_le_bytes();
+
+    // Safety: These ports belong to the PIT and the speaker, which nothing else uses
+    unsafe {
+        outb(GATE, inb(GATE) & !(GATE_ENABLE | SPEAKER_ENABLE));
+        outb(COMMAND, CHANNEL_2_ONE_SHOT);
+        outb(CHANNEL_2, low);
+        outb(CHANNEL_2, high);
+
+        // The count starts on the rising edge of the gate, and the output goes high when it ends
+        outb(GATE, inb(GATE) | GATE_ENABLE);
+        while inb(GATE) & OUTPUT == 0 {
+            spin_loop();
+        }
+        outb(GATE, inb(GATE) & !GATE_ENABLE);
+    }
+}
+
+/// Busy-waits for `duration` using channel 2, which works without any setup.
+pub fn wait(duration: Duration) {
+    let mut remaining = duration;
+    while !remaining.is_zero() {
+        let chunk = remaining.min(MAX_WAIT);
+        let ticks = u16::try_from(chunk.as_nanos() * u128::from(FREQUENCY) / 1_000_000_000)
+            .expect("Waits are short enough for the 16-bit counter");
+        if ticks > 0 {
+            wait_ticks(ticks);
+        }
+        remaining -= chunk;
+    }
+}
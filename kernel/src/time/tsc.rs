@@ -0,0 +1,65 @@
+//! The time stamp counter.
+//!
+//! Every CPU counts cycles in its TSC, which is the cheapest clock to read. It is the preferred
+//! clock source when it is *invariant*, i.e. runs at a constant rate regardless of frequency
+//! scaling or sleep states. Otherwise it is a last resort: durations are off when the frequency
+//! changes, but unlike the PIT it keeps counting when nothing reads it. Its frequency is
+//! enumerated by CPUID on recent CPUs, and otherwise measured against another clock source.
+
+use core::time::Duration;
+
+use crate::{
+    arch::{cpuid, registers},
+    time::ClockSource,
+};
+
+/// How long the TSC is measured against the reference, when its frequency isn't enumerated.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+pub struct Tsc {
+    frequency: u64,
+    invariant: bool,
+}
+
+impl Tsc {
+    /// Returns the TSC as a clock source, calibrating it against `reference` if the CPU doesn't
+    /// enumerate its frequency.
+    pub fn new(reference: &dyn ClockSource) -> Self {
+        let frequency = cpuid::tsc_frequency().unwrap_or_else(|| calibrate(reference));
+        Self {
+            frequency,
+            invariant: cpuid::has_invariant_tsc(),
+        }
+    }
+}
+
+/// Measures the TSC frequency against `reference`.
+fn calibrate(reference: &dyn ClockSource) -> u64 {
+    let ticks = super::nanos_to_ticks(CALIBRATION_TIME, reference.frequency());
+    let reference_start = reference.read();
+    let start = registers::rdtsc();
+    while reference.read() - reference_start < ticks {
+        core::hint::spin_loop();
+    }
+    let cycles = registers::rdtsc() - start;
+    let elapsed = reference.read() - reference_start;
+    cycles * reference.frequency() / elapsed
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "TSC"
+    }
+
+    fn rating(&self) -> u32 {
+        if self.invariant { 300 } else { 50 }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        registers::rdtsc()
+    }
+}